@@ -2,26 +2,46 @@
 //! be directly called by the user.
 
 use std::collections::{HashMap, HashSet};
+use std::env;
+use std::ffi::OsStr;
 use std::fs::{self, File};
 use std::io::{self, Write};
+use std::os::unix::fs::{lchown, symlink, MetadataExt, OpenOptionsExt, PermissionsExt};
 use std::path::Path;
 use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
 use std::time::Duration;
 
 use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
 use glob::glob;
-use http_req::request;
-use indicatif::{ProgressBar, ProgressStyle};
-use nix::unistd::Uid;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use nix::unistd::{chown, mkdtemp, Gid, Uid};
 use serde::Deserialize;
 
-use crate::{info_fmt, info_ident_fmt, ARC_PATH, CACHE, CFG};
+use crate::{bin_dir, build_user, info_fmt, info_ident_fmt, offline, user_mode, ARC_PATH, CACHE, CFG, DB, PREFIX};
 use crate::args;
 use crate::bars;
+use crate::checksum::{self, Algorithm};
+use crate::config::ConflictPolicy;
+use crate::extract::{self, Compression, Kind};
+use crate::http;
 use crate::log;
+use crate::plan::{self, Plan, PlanPackage};
+use crate::sandbox;
+use crate::store::Store;
+use crate::triggers;
 use crate::util;
 
+/// Scripts that a package can provide to run when it is installed or removed.
+pub const HOOKS: [&str; 4] = ["pre-install", "post-install", "pre-remove", "post-remove"];
+
+/// Variables that are passed on from the environment to build scripts. All
+/// others are removed, so that builds don't depend on who runs them.
+pub const BUILD_ENV_KEEP: [&str; 8] = ["PATH", "HOME", "USER", "LOGNAME", "TERM", "LANG", "LC_ALL", "TZ"];
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Package {
     pub meta: PackMeta,
@@ -38,24 +58,142 @@ pub struct Package {
     pub sources: Vec<String>,
 }
 
+impl Package {
+    /// Get the urls of all sources and patches, with the placeholders {name},
+    /// {version}, {major} and {minor} filled in.
+    pub fn source_urls(&self) -> Result<Vec<String>> {
+        self.meta.all_sources().iter().map(|x| self.expand_url(x)).collect()
+    }
+
+    /// Get the alternative urls of each source, with placeholders filled in
+    /// like source_urls. Patches have none.
+    pub fn source_mirrors(&self) -> Result<Vec<Vec<String>>> {
+        let mut res = vec![];
+        for source in &self.meta.sources {
+            res.push(source.mirrors().iter().map(|x| self.expand_url(x)).collect::<Result<_>>()?);
+        }
+
+        Ok(res)
+    }
+
+    /// Fill in the placeholders in a url.
+    fn expand_url(&self, url: &str) -> Result<String> {
+        let version = &self.meta.version;
+        let parts: Vec<&str> = version.split('.').collect();
+        let name = dir_name(&self.dir, &self.name);
+
+        let mut url = url.replace("{name}", &name).replace("{version}", version).replace("{major}", parts[0]);
+        if url.contains("{minor}") {
+            let minor = parts.get(1).context(format!("Version {version} of package {name} has no minor version for {url}"))?;
+            url = url.replace("{minor}", minor);
+        }
+
+        Ok(url)
+    }
+}
+
+/// Get the name of a package from its directory, since the package may have
+/// been given as a path. Falls back to the given name.
+pub fn dir_name(dir: &str, name: &str) -> String {
+    fs::canonicalize(dir)
+        .ok()
+        .and_then(|x| Some(x.file_name()?.to_str()?.to_string()))
+        .unwrap_or(name.to_string())
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct PackMeta {
     pub version: String,
     pub maintainer: String,
-    pub sources: Vec<String>,
+    pub sources: Vec<Source>,
     pub checksums: Vec<String>,
     pub strip: Option<bool>,
+    pub sandbox: Option<bool>,
+    /// Patches to apply after the sources are extracted, in addition to any
+    /// sources ending in .patch or .diff.
+    #[serde(default)]
+    pub patches: Vec<String>,
+}
+
+impl PackMeta {
+    /// All files to download and verify: the sources followed by the patches.
+    /// The checksums cover both, in the same order.
+    pub fn all_sources(&self) -> Vec<String> {
+        self.sources.iter().map(|x| x.url()).chain(&self.patches).cloned().collect()
+    }
+}
+
+/// A source in package.toml, which is either a url, or a table with a url and
+/// options for downloading and extracting it, e.g.
+/// { url = "https://...", mirrors = ["https://..."], strip_components = 0, dest = "subdir" }
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Source {
+    Url(String),
+    Table(SourceTable),
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SourceTable {
+    pub url: String,
+    /// Alternative urls for the same file, which are tried in order if it
+    /// can't be downloaded from url.
+    #[serde(default)]
+    pub mirrors: Vec<String>,
+    /// How many leading path components to remove from archive entries. By
+    /// default, a single top-level directory is removed.
+    pub strip_components: Option<usize>,
+    /// A subdirectory of the src dir to extract or copy to.
+    pub dest: Option<String>,
+}
+
+impl Source {
+    pub fn url(&self) -> &String {
+        match self {
+            Source::Url(x) => x,
+            Source::Table(x) => &x.url,
+        }
+    }
+
+    pub fn mirrors(&self) -> &[String] {
+        match self {
+            Source::Url(_) => &[],
+            Source::Table(x) => &x.mirrors,
+        }
+    }
+
+    pub fn strip_components(&self) -> Option<usize> {
+        match self {
+            Source::Url(_) => None,
+            Source::Table(x) => x.strip_components,
+        }
+    }
+
+    /// Get the directory to extract or copy this source to, which must be
+    /// inside the src dir.
+    pub fn dest_dir(&self, src_dir: &String) -> Result<String> {
+        let Source::Table(SourceTable { dest: Some(dest), .. }) = self else {
+            return Ok(src_dir.clone());
+        };
+
+        if Path::new(dest).components().any(|x| !matches!(x, std::path::Component::Normal(_))) {
+            bail!("Source destination {dest} must be a relative path inside the src dir");
+        }
+
+        Ok(format!("{src_dir}/{dest}"))
+    }
 }
 
 /// Check if a specific version of a package is installed.
 pub fn is_installed(pack: &String, version: &String) -> Result<bool> {
-    let mut path = glob(&format!("/var/cache/moss/installed/{pack}@{version}"))?;
+    let mut path = glob(&format!("{}/installed/{pack}@{version}", *DB))?;
     Ok(path.next().is_some())
 }
 
 /// Check if a file is tracked by any installed packages.
 pub fn is_tracked(file: &String) -> Result<Option<String>> {
-    for f in fs::read_dir("/var/cache/moss/installed/")? {
+    for f in fs::read_dir(format!("{}/installed/", *DB))? {
         let uf = f?;
         let content = fs::read_to_string(&uf.path())?;
         if content.contains(&format!("{file}\n")) {
@@ -271,26 +409,44 @@ pub fn download_all(
         }).len(),
     };
 
-    if let Some(mut n) = pack_toml {
-        // Packages have been parsed somewhere else and provided here. Just
-        // read sources for each package and download.
-        for pack in n.iter_mut() {
-            let sources = download_one(&pack.meta.sources, &pack.name, &pack.dir, force, longest)?;
-            pack.sources = sources;
-        }
+    // Packages may have been parsed somewhere else and provided here,
+    // otherwise parse them first.
+    let mut pack_toml = match pack_toml {
+        Some(x) => x,
+        None => parse_package(packs)?,
+    };
 
-        return Ok(n);
-    } else {
-        // Packages have not already been parsed, so parse packages then
-        // download sources for each package.
-        let mut pack_toml = parse_package(packs)?;
-        for pack in pack_toml.iter_mut() {
-            let sources = download_one(&pack.meta.sources, &pack.name, &pack.dir, force, longest)?;
-            pack.sources = sources;
-        }
+    // Nothing can be downloaded again when offline.
+    let force = force && !offline();
+
+    // Work out where each source goes, and collect the remote ones so that
+    // they can be downloaded together.
+    let mut pending = Pending::default();
+    for pack in pack_toml.iter_mut() {
+        pack.sources = download_one(pack, force, longest, &mut pending)?;
+    }
 
-        return Ok(pack_toml);
+    if !pending.missing.is_empty() {
+        bail!(
+            "Can't work offline, {} source(s) are missing:\n    {}",
+            pending.missing.len(),
+            pending.missing.join("\n    "),
+        );
     }
+
+    fetch_all(&pending, longest)?;
+
+    // Downloads with a checksum were checked against it, so they can go in
+    // the store.
+    let store = Store::from_config();
+    for download in &pending.downloads {
+        if let Some(sum) = &download.checksum {
+            let (algorithm, hash) = checksum::parse(sum)?;
+            store.insert(&download.filename, algorithm, &hash)?;
+        }
+    }
+
+    Ok(pack_toml)
 }
 
 /// Given the parsed TOML data for some packages, recursively identify all
@@ -434,17 +590,152 @@ pub fn checksums_all(
 /// Build packages given their parsed TOML data. The following steps are
 /// performed for each package:
 /// 1. Create cache directories for the package source and the destdir.
-/// 2. Extract archives (.tar.*) to the src directory, and copy all other files.
-/// 3. Execute the build script inside the src directory, passing the destdir
-///    and the package version as $1 and $2, respectively, and:
+/// 2. Extract archives and compressed files to the src directory (see
+///    extract.rs), check out git sources, and copy all other files, then apply
+///    patches (see apply_patch).
+/// 3. Execute the build script inside the src directory, passing the destdir,
+///    the package version and the install prefix as $1, $2 and $3,
+///    respectively, in a clean environment (see build_env), and:
+///      - If sandboxing is enabled, run it inside a sandbox (see sandbox.rs).
+///      - If not running as root, or if sandboxed, run it under fakeroot, so
+///        that ownership set by the build script is kept.
 ///      - If the 'v' flag was provided, tee the output to stdout and log.txt.
 ///      - Otherwise, pipe the output to log.txt.
-/// 4. Generate a package manifest using a glob of the destdir, and write it to
-///    destdir/var/cache/moss/installed/<name>@<version>.
-/// 5. Generate a tarball of the destdir and save it in the cache directory.
+/// 4. Copy any install and removal hooks from the package directory to
+///    destdir/<db>/hooks/<name>, where <db> is /var/cache/moss, or
+///    $XDG_DATA_HOME/moss for user installs, and record the commit of each
+///    git source in destdir/<db>/revisions/<name>.
+/// 5. Generate a package manifest using a glob of the destdir, and write it to
+///    destdir/<db>/installed/<name>@<version>.
+/// 6. Generate a tarball of the destdir and save it in the cache directory,
+///    with the ownership and permissions recorded by fakeroot.
+///
+/// If moss is running as root and build_user is set, all of this is done in a
+/// child process running as that user, in a fresh dir under the system temp
+/// dir instead of the cache, which stays owned by root. The sources are copied
+/// into that dir before it is handed over (see stage_sources), and the
+/// tarballs are copied back into the cache afterwards (see collect_tarball).
 pub fn build_all(
     pack_toml: &Vec<Package>,
     args: &crate::args::Cmd,
+) -> Result<()> {
+    let Some(user) = build_user()? else {
+        return build_packages(pack_toml, args, &format!("{}/build", *CACHE), &bin_dir(&CACHE));
+    };
+
+    // The dir itself stays owned by root, so that the build user can't move
+    // it and point the paths used below somewhere else.
+    let work = mkdtemp(&env::temp_dir().join("moss-build-XXXXXX"))
+        .context("Couldn't create build directory")?
+        .display()
+        .to_string();
+    fs::set_permissions(&work, fs::Permissions::from_mode(0o755))
+        .context(format!("Couldn't set permissions of {work}"))?;
+
+    let build_root = format!("{work}/build");
+    fs::create_dir(&build_root).context(format!("Couldn't create directory {build_root}"))?;
+
+    let mut staged = pack_toml.clone();
+    for toml in &mut staged {
+        stage_sources(toml, &format!("{build_root}/{}", toml.name))?;
+    }
+
+    // Nothing in the dir is writable by the build user yet. Symlinks among
+    // the staged sources are changed themselves, not followed.
+    let status = Command::new("chown")
+        .args(["-R", "-h", &format!("{}:{}", user.uid, user.gid), &build_root])
+        .status()
+        .context(format!("Couldn't change ownership of {build_root}"))?;
+
+    if !status.success() {
+        bail!("Couldn't change ownership of {build_root} to {}", user.name);
+    }
+
+    util::run_as(user, || build_packages(&staged, args, &build_root, &build_root))?;
+
+    let bin_dir = bin_dir(&CACHE);
+    fs::create_dir_all(&bin_dir).context(format!("Couldn't create directory {bin_dir}"))?;
+    for toml in pack_toml {
+        let tarball = format!("{}@{}.tar.gz", toml.name, toml.meta.version);
+        collect_tarball(&format!("{build_root}/{tarball}"), &format!("{bin_dir}/{tarball}"))?;
+    }
+
+    fs::remove_dir_all(&work).context(format!("Couldn't remove build directory {work}"))
+}
+
+/// Copy the sources of a package into its build dir, which the build user can
+/// read unlike the cache, and point the package at the copies. Git sources are
+/// exported from their mirrors into the build dir, where build_packages picks
+/// them up.
+fn stage_sources(toml: &mut Package, build_dir: &str) -> Result<()> {
+    let stage_dir = format!("{build_dir}/sources");
+    fs::create_dir_all(&stage_dir).context(format!("Couldn't create directory {stage_dir}"))?;
+
+    for file in &mut toml.sources {
+        if let Some((mirror, commit)) = parse_git_source(file) {
+            export_git(mirror, commit, &format!("{build_dir}/{commit}.tar"))?;
+            continue;
+        }
+
+        let (prefix, path) = match file.strip_prefix("tar+") {
+            Some(x) => ("tar+", x),
+            None => ("", file.as_str()),
+        };
+
+        let copy = format!("{stage_dir}/{}", path.split('/').next_back().unwrap());
+        fs::copy(path, &copy).context(format!("Couldn't copy {path} to {stage_dir}"))?;
+        *file = format!("{prefix}{copy}");
+    }
+
+    Ok(())
+}
+
+/// Export a commit from a git mirror as a tarball.
+fn export_git(mirror: &str, commit: &str, archive: &str) -> Result<()> {
+    let status = Command::new("git")
+        .args(["-C", mirror, "archive", "--format=tar", "-o", archive, commit])
+        .status()
+        .context(format!("Couldn't check out {commit} from {mirror}"))?;
+
+    if !status.success() {
+        bail!("Couldn't check out {commit} from {mirror}");
+    }
+
+    Ok(())
+}
+
+/// Copy a tarball made by the build user into the cache. The build user owns
+/// the dir the tarball is in, so it isn't opened if it has been replaced with
+/// a symlink or anything other than a regular file.
+fn collect_tarball(file: &str, target: &str) -> Result<()> {
+    let mut src = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(nix::libc::O_NOFOLLOW | nix::libc::O_NONBLOCK)
+        .open(file)
+        .context(format!("Couldn't open {file}"))?;
+
+    if !src.metadata().context(format!("Couldn't read metadata of {file}"))?.is_file() {
+        bail!("Couldn't copy {file}: not a regular file");
+    }
+
+    let _ = fs::remove_file(target);
+    let mut dest = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(target)
+        .context(format!("Couldn't create file {target}"))?;
+
+    io::copy(&mut src, &mut dest).context(format!("Couldn't copy {file} to {target}"))?;
+    Ok(())
+}
+
+/// Build packages as the current user (see build_all), in dirs under
+/// build_root, and save the tarballs in out_dir.
+fn build_packages(
+    pack_toml: &[Package],
+    args: &crate::args::Cmd,
+    build_root: &str,
+    out_dir: &str,
 ) -> Result<()> {
     for (i, toml) in pack_toml.iter().enumerate() {
         let name = &toml.name;
@@ -453,7 +744,7 @@ pub fn build_all(
         info_fmt!("\x1b[36m{}\x1b[0m Building package ({}/{})", name, i + 1, pack_toml.len());
 
         // Create cache directories for src and destdir.
-        let build_dir = format!("{}/build/{name}", *CACHE);
+        let build_dir = format!("{build_root}/{name}");
         let src_dir = format!("{build_dir}/src");
         let dest_dir = format!("{build_dir}/dest");
         fs::create_dir_all(&src_dir).context(format!("Couldn't create directory {src_dir}"))?;
@@ -461,27 +752,49 @@ pub fn build_all(
 
         info_fmt!("\x1b[36m{}\x1b[0m Extracting sources", name);
 
-        for file in &toml.sources {
-            if file.starts_with("tar+") {
-                // Don't extract this tarball, just copy it as-is.
-                let file = &file[4..];
-                let basename = file.split('/').last().unwrap();
-                fs::copy(file, format!("{src_dir}/{basename}"))
-                    .context(format!("Couldn't copy {file} to build dir"))?;
-            } else if file.contains(".tar") {
-                // This is a tarball, extract it to srcdir.
-                Command::new("tar")
-                    .args(["xf", file, "-C", &src_dir, "--strip-components=1"])
-                    .status()
-                    .context(format!("Failed to untar {file}"))?;
+        let mut patches = vec![];
+        for (j, file) in toml.sources.iter().enumerate() {
+            // Patches are applied once everything is extracted.
+            let source = match toml.meta.sources.get(j) {
+                Some(x) if !is_patch(file) => x,
+                _ => {
+                    patches.push(file);
+                    continue;
+                },
+            };
+
+            let target_dir = source.dest_dir(&src_dir)?;
+            fs::create_dir_all(&target_dir).context(format!("Couldn't create directory {target_dir}"))?;
+
+            if let Some((mirror, commit)) = parse_git_source(file) {
+                // Check out the commit by exporting it from the mirror, unless
+                // it was already exported for the build user. There is no
+                // top-level directory to strip by default.
+                let archive = format!("{build_dir}/{commit}.tar");
+                if fs::symlink_metadata(&archive).is_err() {
+                    export_git(mirror, commit, &archive)?;
+                }
+
+                let strip = Some(source.strip_components().unwrap_or(0));
+                extract::extract(&archive, Kind::Tar(Compression::None), &target_dir, strip)?;
+                fs::remove_file(&archive).context(format!("Couldn't remove {archive}"))?;
+            } else if let (false, Some(kind)) = (file.starts_with("tar+"), extract::kind(file)) {
+                // This is an archive or compressed file, extract it.
+                extract::extract(file, kind, &target_dir, source.strip_components())?;
             } else {
-                // This is not a tarball, just copy it as-is.
+                // This is not an archive, or shouldn't be extracted (tar+), so
+                // just copy it as-is.
+                let file = file.strip_prefix("tar+").unwrap_or(file);
                 let basename = file.split('/').last().unwrap();
-                fs::copy(file, format!("{src_dir}/{basename}"))
+                fs::copy(file, format!("{target_dir}/{basename}"))
                     .context(format!("Couldn't copy {file} to build dir"))?;
             }
         }
 
+        for patch in patches {
+            apply_patch(name, patch, &src_dir)?;
+        }
+
         info_fmt!("\x1b[36m{}\x1b[0m Running build script", name);
         if args.verbose { eprintln!(); }
 
@@ -491,8 +804,32 @@ pub fn build_all(
 
         // Create log.txt to store the build log.
         let log_file = File::create(format!("{dest_dir}/../log.txt"))?;
-        let mut build_cmd = Command::new(build_script);
-        build_cmd.arg(&dest_dir).arg(&version).current_dir(src_dir);
+
+        // Ownership and modes set under fakeroot are saved to a state file in
+        // the src dir, which is the only writable path outside the destdir
+        // in a sandbox. It is loaded again to strip and package the files.
+        let sandboxed = toml.meta.sandbox.unwrap_or(CFG.sandbox.unwrap_or(false));
+        let fake_state = format!("{src_dir}/.fakeroot");
+        let fake = use_fakeroot(sandboxed);
+        let mut build_cmd = if fake {
+            fakeroot(&fake_state, &build_script)
+        } else {
+            Command::new(&build_script)
+        };
+
+        build_cmd.arg(&dest_dir).arg(version).arg(&*PREFIX).current_dir(&src_dir);
+
+        build_cmd.env_clear().envs(build_env(toml, &src_dir, &dest_dir)?);
+        if fake {
+            // Don't attempt real chown calls at all. Inside the sandbox, they
+            // fail with EINVAL instead of the EPERM that fakeroot ignores.
+            build_cmd.env("FAKEROOTDONTTRYCHOWN", "1");
+        }
+
+        // Isolate the build from the rest of the system if requested.
+        if sandboxed {
+            sandbox::apply(&mut build_cmd, &build_dir, dir, &src_dir, &dest_dir)?;
+        }
 
         let build_status = if !(args.verbose || CFG.verbose_builds) {
             // This is the default behavior if the 'v' flag wasn't given. Just
@@ -536,10 +873,18 @@ pub fn build_all(
         // Strip unneeded symbols from binaries to reduce the package size.
         if toml.meta.strip.unwrap_or(CFG.strip) {
             info_fmt!("\x1b[36m{}\x1b[0m Stripping binaries", name);
+            let mut files = vec![];
             for file in glob(&format!("{dest_dir}/**/*"))? {
-                let path = format!("{}", file?.display());
-                let _ = Command::new("strip")
-                    .args(["--strip-unneeded", &path])
+                files.push(format!("{}", file?.display()));
+            }
+
+            // Strip in batches, since starting fakeroot for every file is
+            // slow. strip carries on past files that aren't binaries.
+            for batch in files.chunks(256) {
+                let mut strip_cmd = if fake { fakeroot(&fake_state, "strip") } else { Command::new("strip") };
+                let _ = strip_cmd
+                    .arg("--strip-unneeded")
+                    .args(batch)
                     .stdout(Stdio::null())
                     .stderr(Stdio::null())
                     .status();
@@ -548,10 +893,33 @@ pub fn build_all(
             info_fmt!("\x1b[36m{}\x1b[0m Not stripping (explicitly disabled)", name);
         }
  
+        // Copy any install and removal hooks into the package, so that they
+        // are still available after the package leaves the repository.
+        let hook_dir = format!("{dest_dir}{}/hooks/{name}", *DB);
+        for hook in HOOKS {
+            if fs::metadata(format!("{dir}/{hook}")).is_ok() {
+                fs::create_dir_all(&hook_dir).context(format!("Couldn't create directory {hook_dir}"))?;
+                fs::copy(format!("{dir}/{hook}"), format!("{hook_dir}/{hook}"))
+                    .context(format!("Couldn't copy {dir}/{hook} to {hook_dir}"))?;
+            }
+        }
+
+        // Record which commit each git source was built from.
+        let revisions: Vec<String> = toml.source_urls()?.iter().zip(&toml.sources)
+            .filter_map(|(url, file)| Some(format!("{url} {}\n", parse_git_source(file)?.1)))
+            .collect();
+
+        if !revisions.is_empty() {
+            let revision_dir = format!("{dest_dir}{}/revisions", *DB);
+            fs::create_dir_all(&revision_dir).context(format!("Couldn't create directory {revision_dir}"))?;
+            fs::write(format!("{revision_dir}/{name}"), revisions.concat())
+                .context(format!("Couldn't write to file {revision_dir}/{name}"))?;
+        }
+
         // Create the package manifest at
-        // destdir/var/cache/moss/installed/<name>@<version>.
+        // destdir/<db>/installed/<name>@<version>.
         info_fmt!("\x1b[36m{}\x1b[0m Generating manifest", name);
-        let manifest_dir = format!("{dest_dir}/var/cache/moss/installed");
+        let manifest_dir = format!("{dest_dir}{}/installed", *DB);
         let manifest = format!("{manifest_dir}/{name}@{version}");
 
         fs::create_dir_all(&manifest_dir)
@@ -585,12 +953,17 @@ pub fn build_all(
         info_fmt!("\x1b[36m{}\x1b[0m Creating tarball", name);
 
         // Create a cache directory to store built package tarballs.
-        let bin_dir = format!("{}/bin", *CACHE);
-        fs::create_dir_all(&bin_dir).context(format!("Couldn't create directory {bin_dir}"))?;
+        fs::create_dir_all(out_dir).context(format!("Couldn't create directory {out_dir}"))?;
 
-        // Create the tarball.
-        Command::new("tar")
-            .args(["czf", &format!("{}/{}@{}.tar.gz", bin_dir, name, version), "."])
+        // Create the tarball. Without fakeroot, everything is owned by root,
+        // like it would be after a build as root.
+        let mut tar_cmd = if fake { fakeroot(&fake_state, "tar") } else { Command::new("tar") };
+        if !fake && !Uid::effective().is_root() && !user_mode() {
+            tar_cmd.args(["--owner=0", "--group=0"]);
+        }
+
+        tar_cmd
+            .args(["--numeric-owner", "-czf", &format!("{}/{}@{}.tar.gz", out_dir, name, version), "."])
             .current_dir(&dest_dir)
             .status()
             .context("Couldn't create tarball of built package")?;
@@ -604,17 +977,391 @@ pub fn build_all(
     Ok(())
 }
 
+/// Check whether a source is a patch, based on its extension.
+pub fn is_patch(file: &str) -> bool {
+    file.ends_with(".patch") || file.ends_with(".diff")
+}
+
+/// Apply a patch to the src directory with 'patch -p1'. If it doesn't apply
+/// cleanly, the error names each failing hunk and the file it belongs to.
+pub fn apply_patch(name: &str, patch: &str, src_dir: &str) -> Result<()> {
+    let patch = patch.strip_prefix("tar+").unwrap_or(patch);
+    let basename = patch.split('/').next_back().unwrap();
+    info_fmt!("\x1b[36m{}\x1b[0m Applying patch {}", name, basename);
+
+    let output = Command::new("patch")
+        .args(["-p1", "--batch", "--forward", "--no-backup-if-mismatch", "-r", "-", "-i", patch])
+        .current_dir(src_dir)
+        .output()
+        .context(format!("Couldn't execute patch for {basename}"))?;
+
+    if output.status.success() {
+        return Ok(());
+    }
+
+    // Work out which hunks failed from the output of patch, which announces
+    // each file before reporting on its hunks.
+    let mut current = String::new();
+    let mut failed = vec![];
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if let Some(x) = line.strip_prefix("patching file ") {
+            current = x.trim_matches('\'').to_string();
+        } else if let Some((hunk, at)) = line.strip_prefix("Hunk #").and_then(|x| x.split_once(" FAILED at ")) {
+            failed.push(format!("hunk #{hunk} of {current} failed at line {}", at.trim_end_matches('.')));
+        } else if line.starts_with("can't find file") || line.contains("Reversed (or previously applied)") {
+            failed.push(line.to_string());
+        }
+    }
+
+    if failed.is_empty() {
+        failed.push(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+
+    bail!("Couldn't apply patch {basename}:\n    {}", failed.join("\n    "));
+}
+
+/// Check whether builds should run under fakeroot. This is needed when
+/// building system packages as a normal user, or in a sandbox, where only one
+/// user exists. User installs are owned by the user anyway.
+fn use_fakeroot(sandboxed: bool) -> bool {
+    if user_mode() || (Uid::effective().is_root() && !sandboxed) {
+        return false;
+    }
+
+    let found = Command::new("fakeroot")
+        .args(["--", "true"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|x| x.success())
+        .unwrap_or(false);
+
+    if !found {
+        log::warn("fakeroot was not found, so packages will only contain files owned by root");
+    }
+
+    found
+}
+
+/// Build a command that runs a program under fakeroot, loading ownership
+/// information from a state file if it exists and saving it back on exit.
+fn fakeroot(state: &String, program: impl AsRef<OsStr>) -> Command {
+    let mut cmd = Command::new("fakeroot");
+    if fs::metadata(state).is_ok() {
+        cmd.args(["-i", state]);
+    }
+
+    cmd.args(["-s", state, "--"]).arg(program);
+    cmd
+}
+
+/// Work out the environment for a build script. This contains the variables
+/// in BUILD_ENV_KEEP, compiler flags from moss.toml, and the following:
+///   - MOSS_PKG_NAME, MOSS_PKG_DIR, MOSS_SRC_DIR and MOSS_DEST_DIR.
+///   - SOURCE_DATE_EPOCH, which is passed on if set, or otherwise the
+///     modification time of package.toml.
+///   - MAKEFLAGS, which always includes -j with the number of jobs (by
+///     default the number of CPUs).
+pub fn build_env(toml: &Package, src_dir: &str, dest_dir: &str) -> Result<Vec<(String, String)>> {
+    let mut vars = vec![];
+    for key in BUILD_ENV_KEEP {
+        if let Ok(x) = env::var(key) {
+            vars.push((key.to_string(), x));
+        }
+    }
+
+    for (key, val) in [("CFLAGS", &CFG.cflags), ("CXXFLAGS", &CFG.cxxflags), ("LDFLAGS", &CFG.ldflags)] {
+        if let Some(x) = val {
+            vars.push((key.to_string(), x.clone()));
+        }
+    }
+
+    let jobs = CFG.jobs.unwrap_or(thread::available_parallelism().map(|x| x.get()).unwrap_or(1));
+    let makeflags = match &CFG.makeflags {
+        Some(x) => format!("-j{jobs} {x}"),
+        None => format!("-j{jobs}"),
+    };
+
+    vars.push(("MAKEFLAGS".into(), makeflags));
+
+    let dir = &toml.dir;
+    let pkg_dir = fs::canonicalize(dir).context(format!("Couldn't canonicalize path {dir}"))?;
+    let epoch = match env::var("SOURCE_DATE_EPOCH") {
+        Ok(x) => x,
+        Err(_) => fs::metadata(format!("{dir}/package.toml"))
+            .and_then(|x| x.modified())
+            .context(format!("Couldn't get modification time of {dir}/package.toml"))?
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs()
+            .to_string(),
+    };
+
+    vars.push(("MOSS_PKG_NAME".into(), toml.name.clone()));
+    vars.push(("MOSS_PKG_DIR".into(), pkg_dir.display().to_string()));
+    vars.push(("MOSS_SRC_DIR".into(), src_dir.to_string()));
+    vars.push(("MOSS_DEST_DIR".into(), dest_dir.to_string()));
+    vars.push(("SOURCE_DATE_EPOCH".into(), epoch));
+
+    Ok(vars)
+}
+
+/// A file shipped by a package that is being installed, which is already
+/// provided by another package.
+#[derive(Clone, Debug)]
+pub struct Conflict {
+    pub file: String,
+    /// Index of the package being installed in the transaction.
+    pub pack: usize,
+    /// The package that currently provides the file, as <name>@<version>.
+    pub owner: String,
+    /// Index of the owning package in the transaction, if it is being
+    /// installed alongside this one.
+    pub owner_idx: Option<usize>,
+}
+
+/// Work out which conflict policy to use. The command line takes precedence
+/// over moss.toml, and since prompting defeats the purpose of the 'y' flag,
+/// files are overwritten in that case, same as answering yes to the prompt.
+pub fn conflict_policy(args: &args::Cmd) -> ConflictPolicy {
+    match args.conflict.or(CFG.conflict).unwrap_or(ConflictPolicy::Prompt) {
+        ConflictPolicy::Prompt if args.yes => ConflictPolicy::Overwrite,
+        x => x,
+    }
+}
+
+/// Check every package in a transaction for files that are already provided
+/// by an installed package, or by another package in the same transaction.
+/// The packages must already be extracted to their temp dirs.
+pub fn find_conflicts(pack_toml: &[Package]) -> Result<Vec<Conflict>> {
+    let mut conflicts = vec![];
+    let mut seen: HashMap<String, usize> = HashMap::new();
+
+    for (i, toml) in pack_toml.iter().enumerate() {
+        let name = &toml.name;
+        let tmp_dir = format!("{}/tmp/{name}", *CACHE);
+        let manifest = format!("{tmp_dir}{}/installed/{name}@{}", *DB, toml.meta.version);
+        let manifest_content = fs::read_to_string(&manifest).context(format!("Couldn't read manifest at {manifest}"))?;
+
+        for line in manifest_content.lines() {
+            // Directories are shared between packages, but files and symlinks
+            // belong to one package.
+            let is_dir = fs::symlink_metadata(format!("{tmp_dir}/{line}")).map(|x| x.is_dir()).unwrap_or(true);
+            if is_dir {
+                continue;
+            }
+
+            if let Some(&j) = seen.get(line) {
+                // Another package in this transaction ships the same file.
+                let other = &pack_toml[j];
+                conflicts.push(Conflict {
+                    file: line.into(),
+                    pack: i,
+                    owner: format!("{}@{}", other.name, other.meta.version),
+                    owner_idx: Some(j),
+                });
+            } else if let Some(n) = is_tracked(&line.into())? {
+                let other_name = n.split('@').collect::<Vec<&str>>()[0];
+                if let Ok(fsmeta) = fs::symlink_metadata(line) {
+                    if !fsmeta.is_dir() && other_name != name {
+                        conflicts.push(Conflict { file: line.into(), pack: i, owner: n, owner_idx: None });
+                    }
+                }
+            }
+
+            seen.insert(line.into(), i);
+        }
+    }
+
+    Ok(conflicts)
+}
+
+/// Make sure that every file in some extracted packages is inside the user
+/// prefix or the user's package database.
+pub fn check_prefix(pack_toml: &Vec<Package>) -> Result<()> {
+    let mut outside = vec![];
+    for toml in pack_toml {
+        let name = &toml.name;
+        let tmp_dir = format!("{}/tmp/{name}", *CACHE);
+        let manifest = format!("{tmp_dir}{}/installed/{name}@{}", *DB, toml.meta.version);
+        let manifest_content = fs::read_to_string(&manifest).context(format!("Couldn't read manifest at {manifest}"))?;
+
+        for line in manifest_content.lines() {
+            let is_dir = fs::symlink_metadata(format!("{tmp_dir}/{line}")).map(|x| x.is_dir()).unwrap_or(false);
+            if !is_dir && !line.starts_with(&format!("{}/", *PREFIX)) && !line.starts_with(&format!("{}/", *DB)) {
+                outside.push(format!("{name}: {line}"));
+            }
+        }
+    }
+
+    if !outside.is_empty() {
+        for file in &outside {
+            log::info_ident(file);
+        }
+
+        bail!("Some files are outside of {} and can't be installed in user mode; does the build script use $3?", *PREFIX);
+    }
+
+    Ok(())
+}
+
+/// Show all the conflicts in a transaction in one list, then settle each one
+/// according to the conflict policy by editing the extracted packages and
+/// their manifests in the temp dir. Nothing outside the cache is touched.
+pub fn resolve_conflicts(
+    pack_toml: &Vec<Package>,
+    conflicts: &Vec<Conflict>,
+    args: &args::Cmd,
+) -> Result<()> {
+    log::warn(&format!("Found {} file conflict(s):", conflicts.len()));
+    for c in conflicts {
+        info_ident_fmt!("\x1b[36m{}\x1b[0m {} (already provided by {})", pack_toml[c.pack].name, c.file, c.owner);
+    }
+
+    eprintln!();
+
+    let policy = conflict_policy(args);
+    if policy == ConflictPolicy::Abort {
+        for toml in pack_toml {
+            let tmp_dir = format!("{}/tmp/{}", *CACHE, toml.name);
+            fs::remove_dir_all(&tmp_dir).context(format!("Couldn't remove temp dir {tmp_dir}"))?;
+        }
+
+        bail!("Aborting due to file conflicts, nothing was installed");
+    }
+
+    // Manifests are edited in memory and written out at the end, so that
+    // several conflicts touching the same manifest don't undo each other.
+    // Updated manifests of installed packages go into the temp dir of the
+    // last package, so they are installed after everything else.
+    let last = pack_toml.last().unwrap();
+    let last_tmp = format!("{}/tmp/{}", *CACHE, last.name);
+    let mut edits: HashMap<String, String> = HashMap::new();
+
+    for c in conflicts {
+        let toml = &pack_toml[c.pack];
+        let tmp_dir = format!("{}/tmp/{}", *CACHE, toml.name);
+        let manifest = format!("{tmp_dir}{}/installed/{}@{}", *DB, toml.name, toml.meta.version);
+
+        let overwrite = match policy {
+            ConflictPolicy::Prompt => log::prompt_yn(
+                &format!("WARNING: File {} is already provided by {}; overwrite it?", c.file, c.owner),
+                33,
+            )?,
+            ConflictPolicy::Overwrite => true,
+            _ => false,
+        };
+
+        if overwrite {
+            // Use the file from this package, and move the other package's
+            // copy into the alternatives store, updating its manifest to point
+            // at the stored copy.
+            let other_name = c.owner.split('@').next().unwrap();
+            let choice = choice_path(other_name, &c.file);
+            match c.owner_idx {
+                Some(j) => {
+                    let other_tmp = format!("{}/tmp/{}", *CACHE, pack_toml[j].name);
+                    let path = format!("{other_tmp}{}/installed/{}", *DB, c.owner);
+                    stash_file(&format!("{other_tmp}/{}", c.file), &format!("{other_tmp}/{choice}"), true)?;
+                    replace_manifest_line(&mut edits, &path, &path, &c.file, &choice)?;
+                },
+                None => {
+                    let read = format!("{}/installed/{}", *DB, c.owner);
+                    let write = format!("{last_tmp}{}/installed/{}", *DB, c.owner);
+                    stash_file(&c.file, &format!("{last_tmp}/{choice}"), false)?;
+                    replace_manifest_line(&mut edits, &read, &write, &c.file, &choice)?;
+                },
+            }
+        } else {
+            // Keep the existing file, and move this package's copy into the
+            // alternatives store instead.
+            let choice = choice_path(&toml.name, &c.file);
+            stash_file(&format!("{tmp_dir}/{}", c.file), &format!("{tmp_dir}/{choice}"), true)?;
+            replace_manifest_line(&mut edits, &manifest, &manifest, &c.file, &choice)?;
+        }
+    }
+
+    for (path, content) in edits {
+        fs::write(&path, content).context(format!("Couldn't write new manifest {path}"))?;
+    }
+
+    Ok(())
+}
+
+/// Replace a line in a manifest that is being edited in memory, reading the
+/// manifest from disk the first time it is touched.
+pub fn replace_manifest_line(
+    edits: &mut HashMap<String, String>,
+    read: &str,
+    write: &str,
+    line: &str,
+    new: &str,
+) -> Result<()> {
+    if !edits.contains_key(write) {
+        let content = fs::read_to_string(read).context(format!("Couldn't read manifest at {read}"))?;
+        edits.insert(write.to_string(), content);
+    }
+
+    let content = edits.get_mut(write).unwrap();
+    *content = content
+        .lines()
+        .map(|x| if x == line { format!("{new}\n") } else { format!("{x}\n") })
+        .collect();
+
+    Ok(())
+}
+
+/// Get the path in the alternatives store for a package's copy of a file,
+/// e.g. /var/cache/moss/choices/busybox>usr>bin>sh for /usr/bin/sh.
+pub fn choice_path(pack: &str, file: &str) -> String {
+    format!("{}/choices/{pack}{}", *DB, file.replace('/', ">"))
+}
+
+/// Turn the name of an entry in the alternatives store back into the package
+/// name and the path it provides.
+pub fn parse_choice(entry: &str) -> Option<(String, String)> {
+    let (pack, path) = entry.split_once('>')?;
+    Some((pack.into(), format!("/{}", path.replace('>', "/"))))
+}
+
+/// Move or copy a file to a new location, creating parent directories as
+/// needed. Symlinks are recreated rather than followed.
+pub fn stash_file(from: &String, to: &String, remove: bool) -> Result<()> {
+    if let Some(parent) = Path::new(to).parent() {
+        fs::create_dir_all(parent).context(format!("Couldn't create directory {}", parent.display()))?;
+    }
+
+    // Renaming only works within a filesystem, so fall back to copying.
+    if remove && fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+
+    if fs::symlink_metadata(from)?.file_type().is_symlink() {
+        let target = fs::read_link(from).context(format!("Couldn't read link {from}"))?;
+        symlink(target, to).context(format!("Couldn't create symlink {to}"))?;
+    } else {
+        fs::copy(from, to).context(format!("Couldn't copy {from} to {to}"))?;
+    }
+
+    if remove {
+        fs::remove_file(from).context(format!("Couldn't remove {from}"))?;
+    }
+
+    Ok(())
+}
+
 /// Install some packages given their parsed TOML data. This does the the
 /// following:
-/// 1. If not running as root, use sudo, doas, or su to become the root user.
-/// 2. Extract the manifest 
-/// 3. Extract the binary tarball to /.
-pub fn install_all(pack_toml: &Vec<Package>) -> Result<()> {
+/// 1. Extract the binary tarball of each package to a temp dir.
+/// 2. Check all the packages for file conflicts, and resolve them according
+///    to the conflict policy before anything is installed.
+/// 3. Hand the rest of the work to a single elevated moss process (see
+///    install_plan), using sudo, doas, or su to become the root user. In user
+///    mode, the files must all be inside the prefix, and no root is needed.
+pub fn install_all(pack_toml: &Vec<Package>, args: &args::Cmd) -> Result<()> {
     for toml in pack_toml {
         let name = &toml.name;
         let version = &toml.meta.version;
-        let bin_file = format!("{}/bin/{name}@{version}.tar.gz", *CACHE);
-        let manifest = format!("./var/cache/moss/installed/{name}@{version}");
+        let bin_file = format!("{}/{name}@{version}.tar.gz", bin_dir(&CACHE));
         let tmp_dir = format!("{}/tmp/{name}", *CACHE);
 
         fs::create_dir_all(&tmp_dir).context(format!("Couldn't create temp dir {tmp_dir}"))?;
@@ -623,168 +1370,341 @@ pub fn install_all(pack_toml: &Vec<Package>) -> Result<()> {
             .args(["xf", &bin_file, "-C", &tmp_dir])
             .status()
             .context(format!("Couldn't extract binary tarball to temp dir"))?;
+    }
 
-        info_fmt!("\x1b[36m{name}\x1b[0m Checking for conflicts");
-        let manifest_content = fs::read_to_string(format!("{tmp_dir}/{manifest}")).context(format!("Couldn't read manifest at {tmp_dir}/{manifest}"))?;
-        for line in manifest_content.lines() {
-            if let Some(n) = is_tracked(&line.into())? {
-                let other_name = n.split('@').collect::<Vec<&str>>()[0];
-                if let Ok(fsmeta) = fs::metadata(line) {
-                    if fsmeta.is_file() && other_name != name {
-                        if log::prompt_yn(&format!("WARNING: File {line} is already tracked by package {other_name}; overwrite it?"), 33)? {
-                            // If the user chooses to use the file from this package, remove the entry
-                            // for that file from the other package's manifest.
-                            let mut other_manifest = fs::read_to_string(&format!("/var/cache/moss/installed/{n}"))
-                                .context(format!("Couldn't read /var/cache/moss/installed/{n}"))?;
-
-                            other_manifest = other_manifest.replace(&(line.to_owned() + "\n"), "");
-                    
-                            let mut other = File::create(format!("{tmp_dir}/var/cache/moss/installed/{n}"))
-                                .context(format!("Couldn't create file {tmp_dir}/var/cache/moss/installed/{n}"))?;
-
-                            other.write_all(other_manifest.as_bytes()).context("Couldn't write new manifest")?;
-                        } else {
-                            // If the user doesn't want to replace the file, remove the file from the
-                            // temp dir and the packge's manifest.
-                            fs::remove_file(format!("{tmp_dir}/{line}")).context(format!("Couldn't remove file {tmp_dir}/{line}"))?;
-                        
-                            let new_content = fs::read_to_string(format!("{tmp_dir}/{manifest}")).context(format!("Couldn't read manifest at {tmp_dir}/{manifest}"))?;
-                            let new_manifest = new_content.replace(&(line.to_owned() + "\n"), "");
-                
-                            let mut this_manifest = File::create(format!("{tmp_dir}/{manifest}"))
-                                .context(format!("Couldn't create file {tmp_dir}/var/cache/moss/installed/{n}"))?;
-
-                            this_manifest.write_all(new_manifest.as_bytes()).context("Couldn't write new manifest")?;
-                        }
-                    }
+    // Nothing is installed as root in user mode, so make sure that every
+    // package stays inside the prefix before going any further.
+    if user_mode() {
+        check_prefix(pack_toml)?;
+    }
+
+    // Look for conflicts in all packages up front, so they can be dealt with
+    // together before any files are installed.
+    log::info("Checking for conflicts");
+    let conflicts = find_conflicts(pack_toml)?;
+    if !conflicts.is_empty() {
+        resolve_conflicts(pack_toml, &conflicts, args)?;
+    }
+
+    // Everything from here on needs root, so hand it off as a plan.
+    let plan = Plan {
+        cache: CACHE.clone(),
+        install: pack_toml.iter().map(|x| PlanPackage {
+            name: x.name.clone(),
+            version: x.meta.version.clone(),
+        }).collect(),
+        ..Default::default()
+    };
+
+    plan::execute(&plan)
+}
+
+/// Install packages that have been extracted and checked for conflicts, as
+/// described by a plan. Must be run as root. For each package:
+/// 1. Restore the ownership and permissions recorded in the binary tarball.
+/// 2. Run the pre-install hook from the temp dir.
+/// 3. Copy the contents of the temp dir to /, preserving ownership and
+///    permissions. Directories that already exist are left alone.
+/// 4. Run the post-install hook.
+///
+/// Then run any triggers matching the installed files.
+pub fn install_plan(plan: &Plan) -> Result<()> {
+    // Keep track of every installed file, for triggers.
+    let mut installed = vec![];
+
+    for (i, pack) in plan.install.iter().enumerate() {
+        let name = &pack.name;
+        let version = &pack.version;
+        let tmp_dir = format!("{}/tmp/{name}", plan.cache);
+
+        let manifest = format!("{tmp_dir}{}/installed/{name}@{version}", *DB);
+        let manifest_content = fs::read_to_string(&manifest).context(format!("Couldn't read manifest at {manifest}"))?;
+        installed.extend(manifest_content.lines().map(|x| x.to_string()));
+
+        // Extracting the tarball as a normal user loses ownership, so put it
+        // back before anything is copied.
+        if !user_mode() {
+            restore_ownership(&format!("{}/{name}@{version}.tar.gz", bin_dir(&plan.cache)), &tmp_dir)?;
+        }
+
+        let install_files = format!("find {tmp_dir}/. ! -type d -exec sh -c 'cp -a \"$0\" \"/${{0#{tmp_dir}}}\"' {{}} \\;");
+
+        let hook_dir = format!("{}/hooks/{name}", *DB);
+        run_hook(name, "pre-install", &format!("{tmp_dir}{hook_dir}/pre-install"), version)?;
+
+        install_dirs(Path::new(&tmp_dir), Path::new("/")).context(format!("Couldn't install {name} to /"))?;
+
+        Command::new("sh")
+            .args(["-c", &install_files])
+            .status()
+            .context(format!("Couldn't install {name} to /"))?;
+
+        // Remove the temp dir.
+        fs::remove_dir_all(&tmp_dir).context(format!("Couldn't remove temp dir {tmp_dir}"))?;
+
+        run_hook(name, "post-install", &format!("{hook_dir}/post-install"), version)?;
+        info_fmt!("Successfully installed {} @ {} ({}/{})", name, version, i + 1, plan.install.len());
+    }
+
+    triggers::run(&installed)?;
+    Ok(())
+}
+
+/// Create the directories in a temp dir under a target dir, with the same
+/// ownership and permissions. Directories that already exist are left alone.
+fn install_dirs(tmp_dir: &Path, target: &Path) -> Result<()> {
+    let list = fs::read_dir(tmp_dir).context(format!("Couldn't read directory {}", tmp_dir.display()))?;
+    for entry in list {
+        let entry = entry.context(format!("Couldn't read directory {}", tmp_dir.display()))?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        let path = target.join(entry.file_name());
+        if fs::metadata(&path).is_err() {
+            let meta = entry.metadata()?;
+            fs::create_dir(&path).context(format!("Couldn't create directory {}", path.display()))?;
+            chown(&path, Some(Uid::from_raw(meta.uid())), Some(Gid::from_raw(meta.gid())))
+                .context(format!("Couldn't change ownership of {}", path.display()))?;
+
+            fs::set_permissions(&path, meta.permissions())
+                .context(format!("Couldn't set permissions of {}", path.display()))?;
+        }
+
+        install_dirs(&entry.path(), &path)?;
+    }
+
+    Ok(())
+}
+
+/// Give the files in a temp dir the ownership and permissions recorded in the
+/// binary tarball they were extracted from. Anything not in the tarball (such
+/// as manifests rewritten by conflict resolution) is owned by root, and files
+/// that were taken out of the temp dir are skipped. Must be run as root.
+pub fn restore_ownership(bin_file: &String, tmp_dir: &String) -> Result<()> {
+    Command::new("chown")
+        .args(["-R", "-h", "0:0", tmp_dir])
+        .status()
+        .context(format!("Couldn't change ownership of {tmp_dir}"))?;
+
+    let file = File::open(bin_file).context(format!("Couldn't open {bin_file}"))?;
+    let mut archive = tar::Archive::new(GzDecoder::new(file));
+
+    for entry in archive.entries().context(format!("Couldn't read {bin_file}"))? {
+        let entry = entry.context(format!("Couldn't read {bin_file}"))?;
+        let header = entry.header();
+        let path = format!("{tmp_dir}/{}", entry.path()?.display());
+
+        let Ok(meta) = fs::symlink_metadata(&path) else {
+            continue;
+        };
+
+        // Change the owner first, since that clears setuid and setgid bits.
+        lchown(&path, Some(header.uid()? as u32), Some(header.gid()? as u32))
+            .context(format!("Couldn't change ownership of {path}"))?;
+
+        if !meta.file_type().is_symlink() {
+            fs::set_permissions(&path, fs::Permissions::from_mode(header.mode()?))
+                .context(format!("Couldn't set permissions of {path}"))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Uninstall packages by removing the files listed in each package's manifest,
+/// running the pre-remove and post-remove hooks before and after, and then any
+/// triggers matching the removed files. Must be run as root.
+pub fn remove_plan(plan: &Plan) -> Result<()> {
+    // Keep track of every removed file, for triggers.
+    let mut removed = vec![];
+
+    for pack in &plan.remove {
+        // Read the package manifest.
+        let mut manifest_glob = glob(&format!("{}/installed/{pack}@*", *DB))
+            .context(format!("Error constructing glob {}/installed/{pack}@*", *DB))?;
+
+        let manifest_path = manifest_glob.next()
+            .context(format!("Package {pack} is not installed"))?
+            .context("Couldn't get manifest path")?;
+
+        let manifest = fs::read_to_string(&manifest_path)
+            .context(format!("Couldn't read manifest of package {pack} at {}", manifest_path.display()))?;
+
+        let version = manifest_path.display().to_string().split('@').next_back().unwrap().to_string();
+        let hook_dir = format!("{}/hooks/{pack}", *DB);
+        run_hook(pack, "pre-remove", &format!("{hook_dir}/pre-remove"), &version)?;
+
+        // The post-remove hook is removed along with the rest of the package,
+        // so keep a copy of it around to run afterwards.
+        let post_remove = format!("{}/tmp/hooks/{pack}/post-remove", plan.cache);
+        if fs::metadata(format!("{hook_dir}/post-remove")).is_ok() {
+            stash_file(&format!("{hook_dir}/post-remove"), &post_remove, false)?;
+        }
+
+        // Since the manifest was generated using a glob, we iterate through
+        // the lines in reverse to remove the deepest files first.
+        removed.extend(manifest.lines().map(|x| x.to_string()));
+        for file in manifest.lines().rev() {
+            if file == format!("{}/installed", *DB) {
+                continue;
+            }
+
+            let _ = Command::new("rmdir")
+                .arg(file)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status();
+
+            if is_tracked(&file.into())?.is_some() {
+                // The file may already be gone if it was a directory that
+                // was removed above.
+                let is_symlink = fs::symlink_metadata(file).map(|x| x.file_type().is_symlink()).unwrap_or(false);
+                if !is_symlink {
+                    let _ = Command::new("rm")
+                        .arg(file)
+                        .stdout(Stdio::null())
+                        .stderr(Stdio::null())
+                        .status();
                 }
+            } else {
+                let _ = Command::new("rm")
+                    .arg(file)
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status();
             }
         }
+
+        run_hook(pack, "post-remove", &post_remove, &version)?;
+        let _ = fs::remove_dir_all(format!("{}/tmp/hooks/{pack}", plan.cache));
+
+        info_fmt!("{pack} Successfully uninstalled package");
     }
 
+    triggers::run(&removed)?;
+    Ok(())
+}
 
-    let su_command = if let Some(x) = &CFG.su_cmd {
-        x.as_str()
-    } else if fs::metadata("/bin/sudo").is_ok() {
-        "sudo"
-    } else if fs::metadata("/bin/doas").is_ok() {
-        "doas"
-    } else if fs::metadata("/bin/ssu").is_ok() {
-        "ssu"
-    } else {
-        ""
+/// Swap which package provides a path, as described by a plan. The file
+/// currently at the path is moved into the alternatives store under the
+/// package that owns it, the chosen package's copy takes its place, and both
+/// manifests are updated. Must be run as root.
+pub fn swap_plan(plan: &Plan) -> Result<()> {
+    let Some((pack, path)) = &plan.swap else {
+        return Ok(());
     };
 
-    if ! Uid::effective().is_root() {
-        info_fmt!("Using {} to become root", su_command);
+    let choice = choice_path(pack, path);
+    let mut manifest_glob = glob(&format!("{}/installed/{pack}@*", *DB))
+        .context(format!("Error constructing glob {}/installed/{pack}@*", *DB))?;
+
+    let manifest = manifest_glob.next()
+        .context(format!("Package {pack} is not installed"))?
+        .context("Couldn't get manifest path")?
+        .display()
+        .to_string();
+
+    let mut edits = HashMap::new();
+
+    // Move the current provider's copy into the alternatives store.
+    if let Some(owner) = is_tracked(path)? {
+        let owner_name = owner.split('@').next().unwrap().to_string();
+        let owner_choice = choice_path(&owner_name, path);
+        let owner_manifest = format!("{}/installed/{owner}", *DB);
+        stash_file(path, &owner_choice, true)?;
+        replace_manifest_line(&mut edits, &owner_manifest, &owner_manifest, path, &owner_choice)?;
     }
 
-    for (i, toml) in pack_toml.iter().enumerate() {
-        let name = &toml.name;
-        let version = &toml.meta.version;
-        let tmp_dir = format!("{}/tmp/{name}", *CACHE);
+    // Put the chosen package's copy in its place.
+    stash_file(&choice, path, true)?;
+    replace_manifest_line(&mut edits, &manifest, &manifest, &choice, path)?;
 
-        let install_dirs = format!("find {tmp_dir}/. -type d -exec sh -c 'mkdir -p \"/${{0#{tmp_dir}}}\"' {{}} \\;");
-        let install_files = format!("find {tmp_dir}/. ! -type d -exec sh -c 'cp -d \"$0\" \"/${{0#{tmp_dir}}}\"' {{}} \\;");
+    for (file, content) in edits {
+        fs::write(&file, content).context(format!("Couldn't write new manifest {file}"))?;
+    }
 
-        if Uid::effective().is_root() {
-            Command::new("sh")
-                .args(["-c", &install_dirs])
-                .status()
-                .context(format!("Couldn't install {name} to /"))?;
+    info_fmt!("{path} is now provided by {pack}");
+    Ok(())
+}
 
-            Command::new("sh")
-                .args(["-c", &install_files])
-                .status()
-                .context(format!("Couldn't install {name} to /"))?;
+/// Run one of a package's hooks if it exists, passing the install root and
+/// the package version as $1 and $2. A failing pre-* hook stops the action,
+/// while a failing post-* hook only produces a warning since the files have
+/// already been changed by then.
+pub fn run_hook(name: &str, hook: &str, path: &str, version: &str) -> Result<()> {
+    if fs::metadata(path).is_err() {
+        return Ok(());
+    }
 
-            // Remove the temp dir.
-            fs::remove_dir_all(&tmp_dir).context(format!("Couldn't remove temp dir {tmp_dir}"))?;
+    // Hooks are run with sh, so they don't need to be executable or have a
+    // shebang.
+    info_fmt!("\x1b[36m{name}\x1b[0m Running {hook} hook");
+    let status = Command::new("sh")
+        .arg(path)
+        .args(["/", version])
+        .status()
+        .context(format!("Couldn't execute {path}"))?;
+
+    if !status.success() {
+        if hook.starts_with("pre-") {
+            bail!("The {hook} hook of package {name} failed");
         } else {
-            match su_command {
-                "sudo" => {
-                    Command::new("sudo")
-                        .args(["chown", "-R", "root:root", &tmp_dir])
-                        .status()
-                        .context(format!("Couldn't change ownership of package files"))?;
-
-                    Command::new("sudo")
-                        .args(["sh", "-c", &install_dirs])
-                        .status()
-                        .context(format!("Couldn't install {name} to /"))?;
-
-                    Command::new("sudo")
-                        .args(["sh", "-c", &install_files])
-                        .status()
-                    .context(format!("Couldn't install {name} to /"))?;
-                },
-                "doas" => {
-                    Command::new("doas")
-                        .args(["chown", "-R", "root:root", &tmp_dir])
-                        .status()
-                        .context(format!("Couldn't change ownership of package files"))?;
-
-                    Command::new("doas")
-                        .args(["sh", "-c", &install_dirs])
-                        .status()
-                        .context(format!("Couldn't install {name} to /"))?;
-
-                    Command::new("doas")
-                        .args(["sh", "-c", &install_files])
-                        .status()
-                    .context(format!("Couldn't install {name} to /"))?;
-                },
-                "ssu" => {
-                    Command::new("ssu")
-                        .args(["--", "chown", "-R", "root:root", &tmp_dir])
-                        .status()
-                        .context(format!("Couldn't change ownership of package files"))?;
-
-                    Command::new("ssu")
-                        .args(["--", "sh", "-c", &install_dirs])
-                        .status()
-                        .context(format!("Couldn't install {name} to /"))?;
-
-                    Command::new("ssu")
-                        .args(["--", "sh", "-c", &install_files])
-                        .status()
-                    .context(format!("Couldn't install {name} to /"))?;
-                },
-                _ => bail!("Couldn't find a command to elevate privileges"),
-            }
-
-            // Remove the temp dir.
-            Command::new(&su_command)
-                .args(["rm", "-rf", &tmp_dir])
-                .status()
-                .context(format!("Couldn't remove temp dir {tmp_dir}"))?;
-
+            log::warn(&format!("The {hook} hook of package {name} failed"));
         }
-
-        info_fmt!("Successfully installed {} @ {} ({}/{})", name, version, i + 1, pack_toml.len());
     }
- 
+
     Ok(())
 }
 
-/// Download the sources for a single package.
-pub fn download_one(
-    urls: &Vec<String>,
-    name: &String,
-    repo_dir: &String,
-    force: bool,
-    pad: usize
-) -> Result<Vec<String>> {
+/// Get the sources for a single package. Sources are saved in a directory for
+/// the package, under their last url segment, or a name chosen with
+/// 'url -> name'. Git sources and local files are fetched right away, while
+/// remote sources that need downloading are added to pending, to be
+/// downloaded with fetch_all from the first of their urls that works (see
+/// mirror_urls). Unless forced, remote sources are checked against their
+/// checksums as they are downloaded (see http::Client::download). When offline,
+/// sources that would have to be fetched are added to the missing list
+/// instead. Sources that fail to be fetched right away are added to the failed
+/// list, so that fetch_all can report them along with failed downloads.
+pub fn download_one(pack: &Package, force: bool, pad: usize, pending: &mut Pending) -> Result<Vec<String>> {
+    let urls = pack.source_urls()?;
+    let mirrors = pack.source_mirrors()?;
+    let checksums = &pack.meta.checksums;
+    let name = &pack.name;
+    let repo_dir = &pack.dir;
+
     let mut fnames = vec![];
-    // Create a cache directory for downloaded sources.
-    let dir = format!("{}/dl", *CACHE);
+    // Create a cache directory for this package's downloaded sources.
+    let dir = format!("{}/dl/{}", *CACHE, dir_name(repo_dir, name));
     fs::create_dir_all(&dir).context(format!("Couldn't create directory {dir}"))?;
 
     for (i, url) in urls.iter().enumerate() {
+        if let Some(x) = url.strip_prefix("git+") {
+            // Git sources are fetched into a mirror rather than the download
+            // cache, and referred to by the resolved commit from here on.
+            if offline() {
+                match resolve_git_mirror(x) {
+                    Some(source) => fnames.push(source),
+                    None => pending.missing.push(format!("{name}: {url}")),
+                }
+            } else {
+                match fetch_git(x, name, force, pad) {
+                    Ok(source) => fnames.push(source),
+                    Err(e) => {
+                        pending.failed.push(format!("{name}: {e:#}"));
+                        fnames.push(url.clone());
+                    },
+                }
+            }
+
+            continue;
+        }
+
+        let (url, filename) = source_file_name(url)?;
         let og_url = url.clone();
-        let mut url = url.clone();
+        let mut url = url;
 
-        let filename = url.split('/').last().unwrap().to_owned();
         let filename = format!("{dir}/{filename}");
 
         // Remove any prefixes from the url.
@@ -795,69 +1715,372 @@ pub fn download_one(
             fnames.push(filename.clone());
         }
 
+        if !(url.starts_with("https://") || url.starts_with("http://")) {
+            // This is a local file, copy it to the download cache every time,
+            // so that changes to it are picked up. Remove the old copy first,
+            // so that a link into the store isn't written through.
+            let _ = fs::remove_file(&filename);
+            if let Err(e) = fs::copy(format!("{repo_dir}/{url}"), filename) {
+                pending.failed.push(format!("{name}: Could not copy local file {name}/{url} to download cache: {e}"));
+            }
+
+            continue;
+        }
+
+        // Sources with a checksum are looked up in the store first, which
+        // may have them from another package or an older version of this one.
+        let stored = match checksums.get(i).map(|x| checksum::parse(x)) {
+            Some(Ok(x)) if !force => Some(x),
+            _ => None,
+        };
+
+        if let Some((algorithm, hash)) = &stored {
+            if Store::from_config().link(&filename, *algorithm, hash)? {
+                info_ident_fmt!("\x1b[36m{: <pad$}\x1b[0m {} already downloaded, skipping", name, url);
+                continue;
+            }
+        }
+
         // If a file is already downloaded and we are not forcing the
-        // download, skip this file.
-        if fs::metadata(filename.clone()).is_ok() &&! force {
+        // download, skip this file. A link to a different store entry is left
+        // over from before the checksum changed, so it is downloaded again.
+        let stale = stored.is_some() && fs::symlink_metadata(&filename).map(|x| x.is_symlink()).unwrap_or(false);
+        if fs::metadata(filename.clone()).is_ok() && !force && !stale {
             info_ident_fmt!("\x1b[36m{: <pad$}\x1b[0m {} already downloaded, skipping", name, url);
             continue;
         }
 
-        if url.starts_with("https://") || url.starts_with("http://") {
-            // This is a remote url, so download it from the internet.
-            // Create a pretty download progress bar.
-            let bar = "[{elapsed_precise}] [{bar:30.magenta/magenta}] ({bytes_per_sec}, ETA {eta})";
-            let bar_spin = "[{elapsed_precise}] [{spinner:.magenta}] ({bytes_per_sec}, ETA {eta})";
-            let bar_fmt = format!("  \x1b[35m->\x1b[0m \x1b[36m{name: <pad$}\x1b[0m {bar} ({}/{}) ({og_url})", i + 1, urls.len());
-            let bar_spin_fmt = format!("  \x1b[35m->\x1b[0m \x1b[36m{name: <pad$}\x1b[0m {bar_spin} ({}/{}) ({og_url})", i + 1, urls.len());
-
-            let bar = ProgressBar::new(1);
-            let bar_style = ProgressStyle::with_template(&bar_fmt).unwrap().progress_chars("-> ");
-            bar.set_style(ProgressStyle::with_template(&bar_spin_fmt).unwrap().tick_strings(&bars::LSPIN));
-            bar.enable_steady_tick(Duration::from_millis(30));
-            
-            loop {
-                let mut body = vec![];
-                // Get the size of the file to be downloaded, if available.
-                let head = request::head(&url)?;
-                let len = head.content_len().unwrap_or(0);
-
-                // Try to download the file.
-                let res = request::get_with_update(&url, &mut body, |x| util::inc_bar(&bar, x as u64, len, &bar_style))
-                    .context(format!("Couldn't connect to {url}"))?;
-
-                if res.status_code().is_success() {
-                    // The file was downloaded successfully, save it and
-                    // move on to the next file.
+        // This is a remote url, so download it from the internet later.
+        // Forced downloads are how checksums get updated, so they can't be
+        // checked against the old ones.
+        if offline() {
+            pending.missing.push(format!("{name}: {url} ({filename})"));
+            continue;
+        }
+
+        let mut candidates = mirror_urls(&url);
+        for x in mirrors.get(i).into_iter().flatten() {
+            candidates.append(&mut mirror_urls(x));
+        }
+
+        pending.downloads.push(Download {
+            name: name.clone(),
+            url,
+            urls: candidates,
+            og_url,
+            filename,
+            checksum: checksums.get(i).filter(|x| *x != "SKIP" && !force).cloned(),
+            index: i + 1,
+            total: urls.len(),
+        });
+    }
+
+    // Return the paths to each downloaded file.
+    Ok(fnames)
+}
+
+/// Split a source into its url and the name it is saved under in the download
+/// cache, which is either chosen with 'url -> name' or the last segment of the
+/// url.
+pub fn source_file_name(source: &str) -> Result<(String, String)> {
+    let (url, rename) = match source.split_once("->") {
+        Some((x, y)) => (x.trim().to_string(), Some(y.trim())),
+        None => (source.to_string(), None),
+    };
+
+    let filename = match rename {
+        Some(x) if x.is_empty() || x.contains('/') => bail!("Invalid file name '{x}' for source {url}"),
+        Some(x) => x.to_owned(),
+        None => url.split('/').next_back().unwrap().to_owned(),
+    };
+
+    Ok((url, filename))
+}
+
+/// Sources that download_one couldn't get right away.
+#[derive(Default)]
+pub struct Pending {
+    /// Remote sources to download with fetch_all.
+    pub downloads: Vec<Download>,
+    /// Sources that would have to be fetched, when offline.
+    pub missing: Vec<String>,
+    /// Git and local sources that couldn't be fetched, as '<name>: <error>'.
+    pub failed: Vec<String>,
+}
+
+/// A remote source to be downloaded by fetch_all.
+pub struct Download {
+    pub name: String,
+    /// The url from package.toml, and every url to try in order.
+    pub url: String,
+    pub urls: Vec<String>,
+    pub og_url: String,
+    pub filename: String,
+    pub checksum: Option<String>,
+    /// The position of the source in its package, and the number of sources
+    /// in the package, for the progress bar.
+    pub index: usize,
+    pub total: usize,
+}
+
+impl Download {
+    /// Download this source, with a progress bar in multi. Returns the url
+    /// that it was downloaded from.
+    pub fn fetch(&self, client: &http::Client, multi: &MultiProgress, pad: usize) -> Result<String> {
+        // Create a pretty download progress bar.
+        let name = &self.name;
+        let bar = "[{elapsed_precise}] [{bar:30.magenta/magenta}] ({bytes_per_sec}, ETA {eta})";
+        let bar_spin = "[{elapsed_precise}] [{spinner:.magenta}] ({bytes_per_sec}, ETA {eta})";
+        let bar_fmt = format!("  \x1b[35m->\x1b[0m \x1b[36m{name: <pad$}\x1b[0m {bar} ({}/{}) ({})", self.index, self.total, self.og_url);
+        let bar_spin_fmt = format!("  \x1b[35m->\x1b[0m \x1b[36m{name: <pad$}\x1b[0m {bar_spin} ({}/{}) ({})", self.index, self.total, self.og_url);
+
+        let bar = multi.add(ProgressBar::new(1));
+        let bar_style = ProgressStyle::with_template(&bar_fmt).unwrap().progress_chars("-> ");
+        bar.set_style(ProgressStyle::with_template(&bar_spin_fmt).unwrap().tick_strings(&bars::LSPIN));
+        bar.enable_steady_tick(Duration::from_millis(30));
+
+        let mut errors = vec![];
+        for url in &self.urls {
+            match client.download(url, &self.filename, self.checksum.as_ref(), &bar, &bar_style) {
+                Ok(_) => {
                     bar.finish();
-                    eprintln!();
-                    let mut out = File::create(&filename).context(format!("Couldn't create file {filename}"))?;
-                    out.write_all(&body).context(format!("Couldn't save downloaded file to {filename}"))?;
+                    return Ok(url.clone());
+                },
+                Err(e) => errors.push(e),
+            }
+        }
+
+        bar.finish_and_clear();
+        if errors.len() == 1 {
+            return Err(errors.remove(0));
+        }
+
+        let errors: Vec<String> = errors.iter().map(|x| format!("{x:#}")).collect();
+        bail!("Couldn't download {} from any of its {} urls:\n    {}", self.url, errors.len(), errors.join("\n    "))
+    }
+}
+
+/// Download the remote sources in pending, several at a time (see the
+/// 'downloads' config option). Every download is attempted even if some of
+/// them fail, and the failures are listed at the end, along with the sources
+/// that download_one already failed to fetch.
+pub fn fetch_all(pending: &Pending, pad: usize) -> Result<()> {
+    let failed = fetch_each(&pending.downloads, pad);
+    for e in &pending.failed {
+        log::warn(e);
+    }
+
+    let total = failed.len() + pending.failed.len();
+    if total > 0 {
+        bail!("Failed to fetch {total} source(s)");
+    }
+
+    Ok(())
+}
+
+/// Download remote sources like fetch_all, but return the index of each
+/// download that failed along with the error, instead of failing.
+pub fn fetch_each(downloads: &[Download], pad: usize) -> Vec<(usize, anyhow::Error)> {
+    if downloads.is_empty() {
+        return vec![];
+    }
+
+    let limit = CFG.downloads.unwrap_or(4).max(1).min(downloads.len());
+    let client = http::Client::from_config();
+    let multi = MultiProgress::new();
+    let next = AtomicUsize::new(0);
+    let failed = Mutex::new(vec![]);
+    let fallbacks = Mutex::new(vec![]);
+
+    thread::scope(|s| {
+        for _ in 0..limit {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                let Some(download) = downloads.get(i) else {
                     break;
-                } else if res.status_code().is_redirect() {
-                    // The request returned a redirect, get the actual
-                    // file location and update the url.
-                    url = res.headers().get("Location").unwrap().to_owned();
-                } else {
-                    // The request returned a different failure code, bail.
-                    bar.finish_and_clear();
-                    bail!(
-                        "Failed to download source {url} ({} {})",
-                        res.status_code(),
-                        res.reason()
-                    );
+                };
+
+                match download.fetch(&client, &multi, pad) {
+                    Ok(url) if url != download.url => fallbacks.lock().unwrap().push((i, url)),
+                    Ok(_) => (),
+                    Err(e) => failed.lock().unwrap().push((i, e)),
                 }
-            }
-        } else if url.starts_with("git+") {
-            bail!("Git sources are not yet supported ({url})");
-        } else {
-            // This is a local file, copy it to the download cache.
-            fs::copy(format!("{repo_dir}/{url}"), filename)
-                .context(format!("Could not copy local file {name}/{url} to download cache"))?;
+            });
         }
+    });
+
+    let mut failed = failed.into_inner().unwrap();
+    failed.sort_by_key(|x| x.0);
+    let mut fallbacks = fallbacks.into_inner().unwrap();
+    fallbacks.sort_by_key(|x| x.0);
+
+    eprintln!();
+    info_fmt!("Downloaded {} of {} source(s)", downloads.len() - failed.len(), downloads.len());
+    for (i, url) in &fallbacks {
+        let download = &downloads[*i];
+        info_ident_fmt!("\x1b[36m{: <pad$}\x1b[0m {} downloaded from {url}", download.name, download.url);
     }
 
-    // Return the paths to each downloaded file.
-    Ok(fnames)
+    for (i, e) in &failed {
+        log::warn(&format!("{}: {:#}", downloads[*i].name, e));
+    }
+
+    failed
+}
+
+/// Get the urls to try for a url: the url with each matching rewrite rule from
+/// the 'mirrors' config option applied, longest prefix first, followed by the
+/// url itself.
+pub fn mirror_urls(url: &str) -> Vec<String> {
+    let mut rules: Vec<(&String, &Vec<String>)> = CFG.mirrors.iter()
+        .flatten()
+        .filter(|(prefix, _)| url.starts_with(prefix.as_str()))
+        .collect();
+
+    rules.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+
+    let mut res = vec![];
+    for (prefix, mirrors) in rules {
+        for mirror in mirrors {
+            res.push(format!("{mirror}{}", &url[prefix.len()..]));
+        }
+    }
+
+    res.push(url.to_string());
+    res
+}
+
+/// Fetch a git source given as <url>[#branch|tag|commit] into a bare mirror
+/// in the cache, and resolve the ref (HEAD by default) to a commit. Returns the
+/// source as git+<mirror>#<commit>. Unless forced, the mirror is only updated
+/// if the ref can't be resolved yet.
+pub fn fetch_git(source: &str, name: &String, force: bool, pad: usize) -> Result<String> {
+    let (url, git_ref) = source.rsplit_once('#').unwrap_or((source, "HEAD"));
+    let mirror = git_mirror(url);
+
+    let exists = fs::metadata(&mirror).is_ok();
+    if exists && !force {
+        if let Ok(commit) = resolve_git_ref(&mirror, git_ref) {
+            info_ident_fmt!("\x1b[36m{: <pad$}\x1b[0m {} already fetched, skipping", name, source);
+            return Ok(format!("git+{mirror}#{commit}"));
+        }
+    }
+
+    let bar_fmt = format!("  \x1b[35m->\x1b[0m \x1b[36m{name: <pad$}\x1b[0m [{{elapsed_precise}}] [{{spinner:.magenta}}] ({source})");
+    let sp = ProgressBar::new_spinner();
+    sp.enable_steady_tick(Duration::from_millis(75));
+    sp.set_style(ProgressStyle::with_template(&bar_fmt).unwrap().tick_strings(&bars::SPIN));
+
+    let mut git_cmd = Command::new("git");
+    if exists {
+        git_cmd.args(["-C", &mirror, "fetch", "--quiet", "--prune", "--tags"]);
+    } else {
+        git_cmd.args(["clone", "--quiet", "--mirror", url, &mirror]);
+    }
+
+    let output = git_cmd.output().context(format!("Couldn't fetch {url} with git"))?;
+    sp.finish();
+    eprintln!();
+
+    if !output.status.success() {
+        bail!("Couldn't fetch {url} with git: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    let commit = resolve_git_ref(&mirror, git_ref).context(format!("Couldn't find {git_ref} in {url}"))?;
+    Ok(format!("git+{mirror}#{commit}"))
+}
+
+/// Get the path of the mirror of a git repository in the cache.
+pub fn git_mirror(url: &str) -> String {
+    format!("{}/git/{}", *CACHE, git_mirror_name(url))
+}
+
+/// Get the name of the mirror of a git repository, which is the name of the
+/// repository followed by part of the hash of its url.
+pub fn git_mirror_name(url: &str) -> String {
+    let repo = url.trim_end_matches('/').split('/').next_back().unwrap().trim_end_matches(".git");
+    format!("{repo}-{}", &blake3::hash(url.as_bytes()).to_string()[..12])
+}
+
+/// Resolve a git source given as <url>[#branch|tag|commit] from its mirror in
+/// the cache, without fetching anything. Returns None if the mirror or the ref
+/// doesn't exist yet.
+pub fn resolve_git_mirror(source: &str) -> Option<String> {
+    let (url, git_ref) = source.rsplit_once('#').unwrap_or((source, "HEAD"));
+    let mirror = git_mirror(url);
+    let commit = resolve_git_ref(&mirror, git_ref).ok()?;
+    Some(format!("git+{mirror}#{commit}"))
+}
+
+/// Resolve a branch, tag or commit to a full commit hash in a git repository.
+pub fn resolve_git_ref(repo: &str, git_ref: &str) -> Result<String> {
+    let output = Command::new("git")
+        .args(["-C", repo, "rev-parse", "--verify", "--quiet", &format!("{git_ref}^{{commit}}")])
+        .output()
+        .context("Couldn't execute git")?;
+
+    if !output.status.success() {
+        bail!("Unknown git ref {git_ref}");
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Get the checksum of a downloaded source: the hash of the file with the
+/// given algorithm, or the commit of a git source.
+pub fn source_checksum(file: &str, algorithm: Algorithm) -> Result<String> {
+    // Git sources are pinned to the commit they resolved to.
+    if let Some((_, commit)) = parse_git_source(file) {
+        return Ok(commit.to_string());
+    }
+
+    // Remove any prefixes from the file name.
+    let file = if &file[3..4] == "+" { &file[4..] } else { file };
+    let hash = checksum::hash_file(file, algorithm)?;
+    Ok(checksum::format(algorithm, &hash))
+}
+
+/// Replace the checksums in a package.toml, keeping the formatting and
+/// comments of the rest of the file.
+pub fn write_checksums(path: &String, hashes: &Vec<String>) -> Result<()> {
+    let content = fs::read_to_string(path).context(format!("Couldn't read {path}"))?;
+    let mut doc: toml_edit::DocumentMut = content.parse().context(format!("Couldn't parse {path}"))?;
+
+    // Put each checksum on its own line.
+    let mut array = toml_edit::Array::new();
+    for hash in hashes {
+        array.push_formatted(toml_edit::Value::from(hash.as_str()).decorated("\n    ", ""));
+    }
+
+    if !hashes.is_empty() {
+        array.set_trailing("\n");
+        array.set_trailing_comma(true);
+    }
+
+    let meta = doc.get_mut("meta")
+        .and_then(|x| x.as_table_like_mut())
+        .context(format!("{path} has no [meta] section"))?;
+
+    match meta.get_mut("checksums").and_then(|x| x.as_value_mut()) {
+        Some(x) => {
+            // Keep any comments around the old value.
+            let decor = x.decor().clone();
+            *x = toml_edit::Value::Array(array);
+            *x.decor_mut() = decor;
+        },
+        None => {
+            meta.insert("checksums", toml_edit::value(array));
+        },
+    }
+
+    fs::write(path, doc.to_string()).context(format!("Couldn't write to {path}"))?;
+    Ok(())
+}
+
+/// Split a fetched git source (git+<mirror>#<commit>) into the mirror and the
+/// commit. Returns None for other sources.
+pub fn parse_git_source(file: &str) -> Option<(&str, &str)> {
+    file.strip_prefix("git+")?.rsplit_once('#')
 }
 
 /// Verify the checksums for a set of files.
@@ -872,26 +2095,58 @@ pub fn verify_checksums(
         bail!("Missing one or more checksums for package {pack}");
     }
 
+    let store = Store::from_config();
+
     for (file, sum) in fnames.iter().zip(checksums) {
+        // Git sources are pinned to a commit instead of hashed, unless the
+        // checksum is 'SKIP', which allows a branch to be followed.
+        if let Some((url, commit)) = parse_git_source(file) {
+            info_ident_fmt!(
+                "\x1b[36m{: <pad$}\x1b[0m {} / {} ({})",
+                pack,
+                &sum[..sum.len().min(10)],
+                &commit[..10],
+                url.split('/').next_back().unwrap(),
+            );
+
+            if sum != "SKIP" && sum != commit {
+                bail!("Commit mismatch for git source {url} (expected {sum}, got {commit})");
+            }
+
+            continue;
+        }
+
         // Remove any prefixes from the filename.
         let file = if &file[3..4] == "+" { &file[4..] } else { &file[..] };
 
-        // Read the file and generate its b3sum.
-        let data: Vec<u8> = fs::read(file).context(format!("Couldn't read file {file}"))?;
-        let hash = blake3::hash(&data);
+        // Hash the file with the algorithm the checksum was written with.
+        // Files that link to the store were checked when they were added to
+        // it, so they are only looked up.
+        let (algorithm, expected) = checksum::parse(&sum.replace("\"", ""))
+            .context(format!("Invalid checksum {sum} for package {pack}"))?;
+        let file = &file.to_string();
+        let hash = if store.is_linked(file, algorithm, &expected) {
+            expected.clone()
+        } else {
+            checksum::hash_file(file, algorithm)?
+        };
 
         info_ident_fmt!(
             "\x1b[36m{: <pad$}\x1b[0m {} / {} ({})",
             pack,
-            &sum[..10],
-            &hash.to_string()[..10],
+            &expected[..expected.len().min(10)],
+            &hash[..10],
             Path::new(file).file_name().unwrap().to_str().unwrap(),
         );
 
         // Compare the generated hash to the provided one.
-        if hash.to_string() != sum.to_string().replace("\"", "") {
+        if hash != expected {
             bail!("Checksum mismatch for file {file}");
         }
+
+        // Move files that were downloaded before the store existed, or copied
+        // from the package, into it.
+        store.insert(file, algorithm, &expected)?;
     }
 
     Ok(())
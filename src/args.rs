@@ -1,14 +1,21 @@
 //! This module contains logic to parse command line arguments.
 
+use crate::checksum::Algorithm;
+use crate::config::ConflictPolicy;
+
 #[derive(Debug)]
 pub enum Op {
+    Alternatives(Option<(String, String)>),
+    Apply(String),
     Build(Vec<String>),
-    Checksum,
+    Checksum(Vec<String>),
     Die(i32, String),
     Download(Vec<String>),
     Find(String),
+    Gc,
     Install(Vec<String>),
     List,
+    Mirror(String, Vec<String>),
     New(String),
     Purge,
     Remove(Vec<String>),
@@ -25,17 +32,86 @@ impl Default for Op {
 #[derive(Debug, Default)]
 pub struct Cmd {
     pub kind: Op,
+    pub algorithm: Option<Algorithm>,
+    pub check: bool,
+    pub conflict: Option<ConflictPolicy>,
+    pub deps: bool,
+    pub dry_run: bool,
+    pub offline: bool,
+    pub older_than: Option<u64>,
     pub sync: bool,
+    pub user: bool,
     pub verbose: bool,
     pub yes: bool,
 }
 
 /// Parse command line arguments.
 pub fn parse(args: &mut Vec<String>) -> Cmd {
+    let mut cmd = Cmd::default();
+
+    // Pull out long options (--name=value) first, so that they can be given
+    // anywhere on the command line.
+    let mut i = 1;
+    while i < args.len() {
+        if !args[i].starts_with("--") {
+            i += 1;
+            continue;
+        }
+
+        let opt = args.remove(i);
+        let (key, val) = match opt.split_once('=') {
+            Some((k, v)) => (k, Some(v)),
+            None => (&opt[..], None),
+        };
+
+        match (key, val) {
+            ("--algorithm", Some(x)) => match x.parse() {
+                Ok(algorithm) => cmd.algorithm = Some(algorithm),
+                Err(e) => {
+                    cmd.kind = Op::Die(1, format!("{e}"));
+                    return cmd;
+                },
+            },
+            ("--conflict", Some(x)) => match x.parse() {
+                Ok(policy) => cmd.conflict = Some(policy),
+                Err(e) => {
+                    cmd.kind = Op::Die(1, format!("{e}"));
+                    return cmd;
+                },
+            },
+            ("--older-than", Some(x)) => match x.parse() {
+                Ok(days) => cmd.older_than = Some(days),
+                Err(_) => {
+                    cmd.kind = Op::Die(1, format!("Invalid number of days '{x}' for option '--older-than'"));
+                    return cmd;
+                },
+            },
+            ("--check", None) => cmd.check = true,
+            ("--deps", None) => cmd.deps = true,
+            ("--dry-run", None) => cmd.dry_run = true,
+            ("--offline", None) => cmd.offline = true,
+            ("--user", None) => cmd.user = true,
+            ("--algorithm", None) | ("--conflict", None) | ("--older-than", None) => {
+                cmd.kind = Op::Die(1, format!("Option '{key}' requires a value"));
+                return cmd;
+            },
+            _ => {
+                cmd.kind = Op::Die(1, format!("Unknown option {key}"));
+                return cmd;
+            },
+        }
+    }
+
     if args.len() > 1 {
-        let mut cmd = Cmd::default();
 
         cmd.kind = 'o: loop { match args[1].as_str() {
+            "a" | "alternatives" => {
+                match args.len() {
+                    2 => break Op::Alternatives(None),
+                    4 => break Op::Alternatives(Some((args[2].clone(), args[3].clone()))),
+                    _ => break Op::Die(1, "Command 'alternatives' takes either no arguments or a package and a path".into()),
+                }
+            },
             "b" | "build" => {
                 if args.len() > 2 {
                     break Op::Build(args[2..].to_vec());
@@ -43,13 +119,7 @@ pub fn parse(args: &mut Vec<String>) -> Cmd {
                     break Op::Die(1, "Missing required argument(s) for command 'build'".into());
                 }
             },
-            "c" | "checksum" => {
-                if args.len() > 2 {
-                    break Op::Die(1, "Too many arguments for command 'checksum'".into());
-                } else {
-                    break Op::Checksum;
-                }
-            },
+            "c" | "checksum" => break Op::Checksum(args[2..].to_vec()),
             "d" | "download" => {
                 if args.len() > 2 {
                     break Op::Download(args[2..].to_vec());
@@ -64,6 +134,7 @@ pub fn parse(args: &mut Vec<String>) -> Cmd {
                     break Op::Die(1, "Missing required argument for command 'find'".into());
                 }
             },
+            "g" | "gc" => break Op::Gc,
             "i" | "install" => {
                 if args.len() > 2 {
                     break Op::Install(args[2..].to_vec());
@@ -71,6 +142,13 @@ pub fn parse(args: &mut Vec<String>) -> Cmd {
                     break Op::Die(1, "Missing required argument(s) for command 'install'".into());
                 }
             },
+            "m" | "mirror" => {
+                if args.len() > 2 {
+                    break Op::Mirror(args[2].clone(), args[3..].to_vec());
+                } else {
+                    break Op::Die(1, "Missing required argument for command 'mirror'".into());
+                }
+            },
             "n" | "new" => {
                 if args.len() > 2 {
                     break Op::New(args[2].clone());
@@ -85,6 +163,13 @@ pub fn parse(args: &mut Vec<String>) -> Cmd {
                     break Op::Die(1, "Missing required argument(s) for command 'remove'".into());
                 }
             },
+            "__apply" => {
+                if args.len() == 3 {
+                    break Op::Apply(args[2].clone());
+                } else {
+                    break Op::Die(1, "Command '__apply' takes exactly one argument".into());
+                }
+            },
             "l" | "list" => break Op::List,
             "p" | "purge" => break Op::Purge,
             "u" | "upgrade" => break Op::Upgrade,
@@ -106,9 +191,7 @@ pub fn parse(args: &mut Vec<String>) -> Cmd {
                 break Op::Die(1, format!("Unknown command {x}"));
             },
         }};
-
-        return cmd;
-    } else {
-        return Cmd::default();
     }
+
+    cmd
 }
@@ -0,0 +1,114 @@
+//! This module contains logic to compute and check checksums of source files.
+//! Checksums are written as <algorithm>:<hex>, where the algorithm is one of
+//! b3 (blake3), sha256 or sha512. A bare hex string is a blake3 checksum.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Error, Result};
+use sha2::{Digest, Sha256, Sha512};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    Blake3,
+    Sha256,
+    Sha512,
+}
+
+impl FromStr for Algorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "b3" | "blake3" => Ok(Algorithm::Blake3),
+            "sha256" => Ok(Algorithm::Sha256),
+            "sha512" => Ok(Algorithm::Sha512),
+            x => bail!("Unknown checksum algorithm '{x}' (expected b3, sha256 or sha512)"),
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Algorithm::Blake3 => write!(f, "b3"),
+            Algorithm::Sha256 => write!(f, "sha256"),
+            Algorithm::Sha512 => write!(f, "sha512"),
+        }
+    }
+}
+
+/// A hasher for any of the supported algorithms, which can be written to
+/// while data is being read or downloaded.
+pub enum Hasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    pub fn new(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            Algorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            Algorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Blake3(x) => { x.update(data); },
+            Hasher::Sha256(x) => x.update(data),
+            Hasher::Sha512(x) => x.update(data),
+        }
+    }
+
+    /// Get the hash as a hex string.
+    pub fn finalize(self) -> String {
+        match self {
+            Hasher::Blake3(x) => x.finalize().to_string(),
+            Hasher::Sha256(x) => format!("{:x}", x.finalize()),
+            Hasher::Sha512(x) => format!("{:x}", x.finalize()),
+        }
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Split a checksum into its algorithm and hex digest.
+pub fn parse(sum: &str) -> Result<(Algorithm, String)> {
+    let (algorithm, hex) = match sum.split_once(':') {
+        Some((x, y)) => (x.parse()?, y),
+        None => (Algorithm::Blake3, sum),
+    };
+
+    Ok((algorithm, hex.to_lowercase()))
+}
+
+/// Write a checksum, leaving out the algorithm for blake3.
+pub fn format(algorithm: Algorithm, hex: &String) -> String {
+    match algorithm {
+        Algorithm::Blake3 => hex.clone(),
+        Algorithm::Sha256 => format!("sha256:{hex}"),
+        Algorithm::Sha512 => format!("sha512:{hex}"),
+    }
+}
+
+/// Hash a file with the given algorithm, returning the hex digest.
+pub fn hash_file(path: &str, algorithm: Algorithm) -> Result<String> {
+    let mut file = File::open(path).context(format!("Couldn't read file {path}"))?;
+    let mut hasher = Hasher::new(algorithm);
+    io::copy(&mut file, &mut hasher).context(format!("Couldn't read file {path}"))?;
+    Ok(hasher.finalize())
+}
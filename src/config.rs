@@ -1,3 +1,7 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use anyhow::{bail, Error};
 use serde::Deserialize;
 
 #[derive(Clone, Debug, Deserialize)]
@@ -5,6 +9,47 @@ pub struct Config {
     pub path: Vec<String>,
     pub verbose_builds: bool,
     pub strip: bool,
+    pub sandbox: Option<bool>,
+    pub build_user: Option<String>,
+    pub cflags: Option<String>,
+    pub cxxflags: Option<String>,
+    pub ldflags: Option<String>,
+    pub makeflags: Option<String>,
+    pub jobs: Option<usize>,
+    pub downloads: Option<usize>,
+    pub timeout: Option<u64>,
+    pub retries: Option<usize>,
+    pub proxy: Option<String>,
+    pub mirrors: Option<HashMap<String, Vec<String>>>,
     pub su_cmd: Option<String>,
     pub cache_dir: Option<String>,
+    pub store_dir: Option<String>,
+    pub conflict: Option<ConflictPolicy>,
+    pub trigger_dir: Option<String>,
+    pub user_prefix: Option<String>,
+}
+
+/// What to do when a package being installed contains a file that is already
+/// provided by another package.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    Prompt,
+    Overwrite,
+    KeepExisting,
+    Abort,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "prompt" => Ok(ConflictPolicy::Prompt),
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            "keep-existing" => Ok(ConflictPolicy::KeepExisting),
+            "abort" => Ok(ConflictPolicy::Abort),
+            x => bail!("Unknown conflict policy '{x}' (expected prompt, overwrite, keep-existing or abort)"),
+        }
+    }
 }
@@ -0,0 +1,278 @@
+//! This module contains logic to extract sources into the src directory
+//! without relying on external tools. Tarballs (optionally compressed with
+//! gzip, xz, bzip2 or zstd), zip archives, and single compressed files are
+//! supported.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use bzip2::read::BzDecoder;
+use flate2::read::GzDecoder;
+use xz2::read::XzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+/// Compression formats that can be used on their own or around a tarball.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Bzip2,
+    Zstd,
+}
+
+/// Kinds of source files that can be extracted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Tar(Compression),
+    Zip,
+    /// A single compressed file, which is decompressed into the src dir with
+    /// its extension removed.
+    Compressed(Compression),
+}
+
+/// Work out how to extract a file from its name. Returns None if the file
+/// should just be copied.
+pub fn kind(file: &str) -> Option<Kind> {
+    let tarballs = [
+        (".tar", Compression::None),
+        (".tar.gz", Compression::Gzip),
+        (".tgz", Compression::Gzip),
+        (".tar.xz", Compression::Xz),
+        (".txz", Compression::Xz),
+        (".tar.bz2", Compression::Bzip2),
+        (".tbz", Compression::Bzip2),
+        (".tbz2", Compression::Bzip2),
+        (".tar.zst", Compression::Zstd),
+        (".tzst", Compression::Zstd),
+    ];
+
+    let compressed = [
+        (".gz", Compression::Gzip),
+        (".xz", Compression::Xz),
+        (".bz2", Compression::Bzip2),
+        (".zst", Compression::Zstd),
+    ];
+
+    if let Some((_, x)) = tarballs.iter().find(|(ext, _)| file.ends_with(ext)) {
+        Some(Kind::Tar(*x))
+    } else if file.ends_with(".zip") {
+        Some(Kind::Zip)
+    } else if let Some((_, x)) = compressed.iter().find(|(ext, _)| file.ends_with(ext)) {
+        Some(Kind::Compressed(*x))
+    } else {
+        None
+    }
+}
+
+/// Wrap a reader in a decoder for the given compression format.
+pub fn decoder<'a>(reader: impl Read + 'a, compression: Compression) -> Result<Box<dyn Read + 'a>> {
+    Ok(match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(GzDecoder::new(reader)),
+        Compression::Xz => Box::new(XzDecoder::new(reader)),
+        Compression::Bzip2 => Box::new(BzDecoder::new(reader)),
+        Compression::Zstd => Box::new(ZstdDecoder::new(reader)?),
+    })
+}
+
+/// Extract a file into a directory. If strip is None, a single top-level
+/// directory shared by every entry is stripped, like 'tar --strip-components=1'
+/// but without losing anything from archives that don't have one.
+pub fn extract(file: &String, kind: Kind, dest: &String, strip: Option<usize>) -> Result<()> {
+    fs::create_dir_all(dest).context(format!("Couldn't create directory {dest}"))?;
+
+    match kind {
+        Kind::Tar(compression) => {
+            let open = || -> Result<Box<dyn Read>> {
+                let reader = BufReader::new(File::open(file).context(format!("Couldn't open {file}"))?);
+                decoder(reader, compression)
+            };
+
+            unpack_tar(open, dest, strip).context(format!("Couldn't extract {file}"))
+        },
+        Kind::Zip => unpack_zip(file, dest, strip).context(format!("Couldn't extract {file}")),
+        Kind::Compressed(compression) => {
+            let basename = Path::new(file).file_stem().unwrap().to_str().unwrap();
+            let out_path = format!("{dest}/{basename}");
+            let reader = BufReader::new(File::open(file).context(format!("Couldn't open {file}"))?);
+            let mut out = File::create(&out_path).context(format!("Couldn't create file {out_path}"))?;
+            io::copy(&mut decoder(reader, compression)?, &mut out).context(format!("Couldn't decompress {file}"))?;
+            Ok(())
+        },
+    }
+}
+
+/// Unpack a tarball, which is opened by calling open. Working out whether
+/// there is a top-level directory to strip takes an extra pass, so the
+/// tarball may be opened twice.
+pub fn unpack_tar(open: impl Fn() -> Result<Box<dyn Read>>, dest: &String, strip: Option<usize>) -> Result<()> {
+    let strip = match strip {
+        Some(x) => x,
+        None => {
+            let mut paths = vec![];
+            for entry in tar::Archive::new(open()?).entries()? {
+                let entry = entry?;
+                if !is_metadata(&entry) {
+                    paths.push(entry.path()?.to_path_buf());
+                }
+            }
+
+            auto_strip(&paths)
+        },
+    };
+
+    let dest_path = Path::new(dest);
+    for entry in tar::Archive::new(open()?).entries()? {
+        let mut entry = entry?;
+        if is_metadata(&entry) {
+            continue;
+        }
+
+        let path = entry.path()?.to_path_buf();
+        let Some(out_path) = strip_path(&path, strip)? else {
+            continue;
+        };
+
+        check_parents(dest_path, &out_path)?;
+        let out_path = dest_path.join(out_path);
+        if let Some(parent) = out_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Hard links point to other entries in the archive, so their targets
+        // have to be stripped too.
+        if entry.header().entry_type().is_hard_link() {
+            let target = entry.link_name()?.context(format!("Hard link {} has no target", path.display()))?;
+            if let Some(target) = strip_path(&target, strip)? {
+                check_parents(dest_path, &target)?;
+                let _ = fs::remove_file(&out_path);
+                fs::hard_link(dest_path.join(target), &out_path)
+                    .context(format!("Couldn't create hard link {}", out_path.display()))?;
+            }
+
+            continue;
+        }
+
+        entry.unpack(&out_path).context(format!("Couldn't unpack {}", path.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Unpack a zip archive.
+pub fn unpack_zip(file: &String, dest: &String, strip: Option<usize>) -> Result<()> {
+    let mut archive = zip::ZipArchive::new(File::open(file)?)?;
+
+    let strip = match strip {
+        Some(x) => x,
+        None => {
+            let paths: Vec<PathBuf> = archive.file_names().map(PathBuf::from).collect();
+            auto_strip(&paths)
+        },
+    };
+
+    let dest_path = Path::new(dest);
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let path = entry.enclosed_name().context(format!("Unsafe path {} in archive", entry.name()))?;
+        let Some(out_path) = strip_path(&path, strip)? else {
+            continue;
+        };
+
+        check_parents(dest_path, &out_path)?;
+        let out_path = dest_path.join(out_path);
+        if entry.is_dir() {
+            fs::create_dir_all(&out_path)?;
+            continue;
+        }
+
+        if let Some(parent) = out_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mode = entry.unix_mode();
+        if mode.map(|x| x & 0o170000 == 0o120000).unwrap_or(false) {
+            // Symlinks are stored as files containing the link target.
+            let mut target = String::new();
+            entry.read_to_string(&mut target)?;
+            let _ = fs::remove_file(&out_path);
+            symlink(target, &out_path).context(format!("Couldn't create symlink {}", out_path.display()))?;
+            continue;
+        }
+
+        let mut out = File::create(&out_path).context(format!("Couldn't create file {}", out_path.display()))?;
+        io::copy(&mut entry, &mut out)?;
+
+        if let Some(x) = mode {
+            fs::set_permissions(&out_path, fs::Permissions::from_mode(x & 0o7777))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether a tar entry only holds metadata for other entries, such as the pax
+/// global header that 'git archive' adds with the commit, rather than a file.
+fn is_metadata<R: Read>(entry: &tar::Entry<R>) -> bool {
+    matches!(
+        entry.header().entry_type(),
+        tar::EntryType::XGlobalHeader | tar::EntryType::XHeader | tar::EntryType::GNULongName | tar::EntryType::GNULongLink
+    )
+}
+
+/// Fail if a path from an archive goes through a symlink inside the dest dir.
+/// Otherwise an archive could add a symlink to a directory elsewhere and then
+/// write files through it.
+fn check_parents(dest: &Path, path: &Path) -> Result<()> {
+    let mut current = dest.to_path_buf();
+    for component in path.parent().into_iter().flat_map(|x| x.components()) {
+        current.push(component);
+        if fs::symlink_metadata(&current).map(|x| x.is_symlink()).unwrap_or(false) {
+            bail!("Unsafe path {} in archive, {} is a symlink", path.display(), current.display());
+        }
+    }
+
+    Ok(())
+}
+
+/// Work out how many components to strip from the paths in an archive: one if
+/// they all share a single top-level directory, otherwise none.
+fn auto_strip(paths: &[PathBuf]) -> usize {
+    let mut top = None;
+    let mut nested = false;
+    for path in paths {
+        let mut components = path.components().filter(|x| *x != Component::CurDir);
+        let Some(first) = components.next() else {
+            continue;
+        };
+
+        match top {
+            None => top = Some(first),
+            Some(x) if x != first => return 0,
+            _ => (),
+        }
+
+        nested |= components.next().is_some();
+    }
+
+    // An archive containing a single file has nothing to strip.
+    if nested { 1 } else { 0 }
+}
+
+/// Remove the first n components of a path from an archive. Returns None if
+/// nothing is left, and fails if the path would end up outside the dest dir.
+fn strip_path(path: &Path, n: usize) -> Result<Option<PathBuf>> {
+    let mut res = PathBuf::new();
+    for component in path.components().filter(|x| *x != Component::CurDir).skip(n) {
+        match component {
+            Component::Normal(x) => res.push(x),
+            _ => bail!("Unsafe path {} in archive", path.display()),
+        }
+    }
+
+    Ok(if res.as_os_str().is_empty() { None } else { Some(res) })
+}
@@ -0,0 +1,281 @@
+//! This module contains logic to find files in the cache that are no longer
+//! needed: sources, source store entries and git mirrors that no package in the
+//! repositories refers to, binary tarballs of versions that are neither
+//! installed nor the latest one in the repositories, and build and tmp dirs
+//! left over from earlier builds.
+
+use std::collections::HashSet;
+use std::fs;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use glob::glob;
+
+use crate::actions::{self, Package};
+use crate::checksum;
+use crate::store::Store;
+use crate::{bin_dir, log, ARC_PATH, CACHE, CFG, DB};
+
+/// Where to look for files that can be removed, and for what still needs them.
+pub struct Layout {
+    pub cache: String,
+    pub store: Store,
+    /// Whether the store is shared with other machines (set with the
+    /// 'store_dir' config option), in which case it is left alone.
+    pub shared_store: bool,
+    /// The dir holding the binary tarballs for the current mode.
+    pub bins: String,
+    /// The repositories whose packages are kept.
+    pub repos: Vec<String>,
+    /// The package database, where installed packages are tracked.
+    pub db: String,
+}
+
+impl Layout {
+    /// Get the layout from moss.toml.
+    pub fn from_config() -> Layout {
+        Layout {
+            cache: CACHE.clone(),
+            store: Store::from_config(),
+            shared_store: CFG.store_dir.is_some(),
+            bins: bin_dir(&CACHE),
+            repos: ARC_PATH.clone(),
+            db: DB.clone(),
+        }
+    }
+}
+
+/// A file or directory in the cache that can be removed.
+#[derive(Debug)]
+pub struct Unused {
+    pub path: String,
+    /// The size of the file, or of everything in the directory.
+    pub size: u64,
+    pub reason: &'static str,
+}
+
+/// What the packages in the repositories (and installed packages) still need
+/// from the cache.
+#[derive(Default)]
+struct Referenced {
+    /// Downloaded sources, as paths in the download cache.
+    sources: HashSet<String>,
+    /// Entries in the source store.
+    stored: HashSet<String>,
+    /// Download cache dirs to keep whole, for packages whose package.toml
+    /// couldn't be read.
+    dl_dirs: HashSet<String>,
+    /// Git mirrors.
+    mirrors: HashSet<String>,
+    /// Binary tarballs, as <name>@<version>.
+    bins: HashSet<String>,
+    /// Packages to keep every binary tarball of.
+    names: HashSet<String>,
+}
+
+/// Find everything in the cache that can be removed. Files and directories
+/// modified less than min_age ago are left alone. If the cache is busy (see
+/// lock_cache), build and tmp dirs and partial downloads may still be in use,
+/// so they are left alone too.
+pub fn unused(layout: &Layout, min_age: Option<Duration>, busy: bool) -> Result<Vec<Unused>> {
+    let mut refs = referenced(layout)?;
+    let mut res = vec![];
+
+    // Sources that no package refers to any more. If nothing in a package's
+    // download dir is used, the whole dir goes.
+    for dir in entries(&format!("{}/dl", layout.cache))? {
+        if refs.dl_dirs.contains(&dir) {
+            refs.stored.extend(entries(&dir)?.iter().filter_map(|x| link_target(x)));
+            continue;
+        }
+
+        // Sources used to be downloaded straight into dl/, before each
+        // package had its own dir.
+        if !fs::metadata(&dir).map(|x| x.is_dir()).unwrap_or(false) {
+            res.push(unused_entry(dir, "unused source"));
+            continue;
+        }
+
+        let files = entries(&dir)?;
+        let partial = |x: &String| x.ends_with(".part");
+        let used = |x: &String| refs.sources.contains(x.strip_suffix(".part").unwrap_or(x));
+        let in_progress = busy && files.iter().any(partial);
+        if !files.iter().any(used) && !in_progress {
+            res.push(unused_entry(dir, "unused sources"));
+            continue;
+        }
+
+        for file in files {
+            if used(&file) {
+                refs.stored.extend(link_target(&file));
+            } else if partial(&file) {
+                if !busy {
+                    res.push(unused_entry(file, "partial download"));
+                }
+            } else {
+                res.push(unused_entry(file, "unused source"));
+            }
+        }
+    }
+
+    // Store entries that neither a package's checksums nor a source that is
+    // kept point to. Part files may belong to another process that is adding
+    // an entry, so they are left alone. A shared store may be used by
+    // packages from other repositories, so nothing is removed from it.
+    let store = if layout.shared_store { vec![] } else { entries(&layout.store.dir)? };
+    for dir in store {
+        for entry in entries(&dir)? {
+            if !refs.stored.contains(&entry) && !entry.ends_with(".part") {
+                res.push(unused_entry(entry, "unused source"));
+            }
+        }
+    }
+
+    for mirror in entries(&format!("{}/git", layout.cache))? {
+        if !refs.mirrors.contains(&mirror) {
+            res.push(unused_entry(mirror, "unused git mirror"));
+        }
+    }
+
+    // Only the tarballs for the current mode are checked, since whether they
+    // are installed depends on the package database of that mode.
+    for bin in entries(&layout.bins)? {
+        if fs::metadata(&bin).map(|x| x.is_dir()).unwrap_or(false) {
+            continue;
+        }
+
+        let file = bin.split('/').next_back().unwrap();
+        let pack = file.strip_suffix(".tar.gz").unwrap_or(file);
+        let name = pack.split('@').next().unwrap();
+        if !refs.bins.contains(pack) && !refs.names.contains(name) {
+            res.push(unused_entry(bin, "old binary"));
+        }
+    }
+
+    // Build and tmp dirs are only needed while a build or an install is
+    // running.
+    if !busy {
+        for dir in entries(&format!("{}/build", layout.cache))? {
+            res.push(unused_entry(dir, "leftover build dir"));
+        }
+
+        for dir in entries(&format!("{}/tmp", layout.cache))? {
+            res.push(unused_entry(dir, "leftover tmp dir"));
+        }
+    }
+
+    if let Some(age) = min_age {
+        let now = SystemTime::now();
+        res.retain(|x| {
+            fs::symlink_metadata(&x.path)
+                .and_then(|x| x.modified())
+                .map(|x| now.duration_since(x).unwrap_or_default() >= age)
+                .unwrap_or(true)
+        });
+    }
+
+    Ok(res)
+}
+
+/// Collect what the packages in the repositories refer to, and which binary
+/// tarballs are installed.
+fn referenced(layout: &Layout) -> Result<Referenced> {
+    let mut refs = Referenced::default();
+
+    for repo in &layout.repos {
+        let Ok(packs) = glob(&format!("{repo}/*/package.toml")) else { continue };
+        for pack in packs.flatten() {
+            let dir = pack.parent().unwrap().display().to_string();
+            let name = dir.split('/').next_back().unwrap().to_string();
+
+            match package_refs(layout, &dir, &name) {
+                Ok((toml, sources, mirrors)) => {
+                    refs.bins.insert(format!("{name}@{}", toml.meta.version));
+                    for sum in &toml.meta.checksums {
+                        if let Ok((algorithm, hash)) = checksum::parse(sum) {
+                            refs.stored.insert(layout.store.path(algorithm, &hash));
+                        }
+                    }
+
+                    refs.sources.extend(sources);
+                    refs.mirrors.extend(mirrors);
+                },
+                Err(e) => {
+                    // Without the package.toml there is no telling which files
+                    // are still needed, so keep all of them.
+                    log::warn(&format!("Keeping cached files for {name}: {e:#}"));
+                    refs.dl_dirs.insert(format!("{}/dl/{}", layout.cache, actions::dir_name(&dir, &name)));
+                    refs.names.insert(name);
+                },
+            }
+        }
+    }
+
+    for pack in glob(&format!("{}/installed/*", layout.db))? {
+        refs.bins.insert(pack?.display().to_string().split('/').next_back().unwrap().to_string());
+    }
+
+    Ok(refs)
+}
+
+/// Get the package in a directory, along with the paths of its sources in the
+/// download cache and the git mirrors it uses.
+fn package_refs(layout: &Layout, dir: &str, name: &str) -> Result<(Package, Vec<String>, Vec<String>)> {
+    let mut toml = actions::parse_package(&vec![dir.to_string()])?.remove(0);
+    toml.name = name.to_string();
+
+    let dl_dir = format!("{}/dl/{}", layout.cache, actions::dir_name(&toml.dir, &toml.name));
+    let mut sources = vec![];
+    let mut mirrors = vec![];
+    for url in toml.source_urls()? {
+        if let Some(x) = url.strip_prefix("git+") {
+            let (url, _) = x.rsplit_once('#').unwrap_or((x, "HEAD"));
+            mirrors.push(format!("{}/git/{}", layout.cache, actions::git_mirror_name(url)));
+        } else {
+            let (_, filename) = actions::source_file_name(&url)?;
+            sources.push(format!("{dl_dir}/{filename}"));
+        }
+    }
+
+    Ok((toml, sources, mirrors))
+}
+
+/// List a directory. A missing directory has no entries.
+fn entries(dir: &str) -> Result<Vec<String>> {
+    let list = match fs::read_dir(dir) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).context(format!("Couldn't read directory {dir}")),
+    };
+
+    let mut res = vec![];
+    for entry in list {
+        let entry = entry.context(format!("Couldn't read directory {dir}"))?;
+        res.push(entry.path().display().to_string());
+    }
+
+    res.sort();
+    Ok(res)
+}
+
+/// Get where a symlink in the download cache points to.
+fn link_target(path: &str) -> Option<String> {
+    fs::read_link(path).ok().map(|x| x.display().to_string())
+}
+
+fn unused_entry(path: String, reason: &'static str) -> Unused {
+    let size = disk_size(&path);
+    Unused { path, size, reason }
+}
+
+/// Get the size of a file, or of everything in a directory. Symlinks aren't
+/// followed.
+pub fn disk_size(path: &str) -> u64 {
+    let Ok(meta) = fs::symlink_metadata(path) else { return 0 };
+    if !meta.is_dir() {
+        return meta.len();
+    }
+
+    let Ok(list) = fs::read_dir(path) else { return 0 };
+    list.flatten().map(|x| disk_size(&x.path().display().to_string())).sum()
+}
@@ -0,0 +1,302 @@
+//! This module contains logic to download files over HTTP(S). Downloads are
+//! streamed to disk and resumed if they are interrupted, failed requests are
+//! retried with a growing delay between attempts, redirects are followed up to
+//! a limit, and requests can go through a proxy.
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Error, Result};
+use indicatif::{ProgressBar, ProgressStyle};
+use url::Url;
+
+use crate::checksum::{self, Algorithm, Hasher};
+use crate::util;
+use crate::CFG;
+
+/// Settings for downloading files.
+#[derive(Clone, Debug)]
+pub struct Client {
+    /// How long to wait to connect, and for each read from the connection.
+    pub timeout: Duration,
+    /// How many times to retry a download that failed for a reason that might
+    /// go away, such as a dropped connection or a server error.
+    pub retries: usize,
+    /// How long to wait before the first retry. The delay doubles after each
+    /// retry.
+    pub backoff: Duration,
+    /// How many redirects to follow before giving up.
+    pub max_redirects: usize,
+    /// The proxy to use for every request. If this is None, the http_proxy,
+    /// https_proxy, all_proxy and no_proxy environment variables are used.
+    pub proxy: Option<String>,
+}
+
+/// Why a download attempt failed.
+enum Failure {
+    /// Something that might not happen again, like a timeout or a server error,
+    /// so the download should be retried.
+    Transient(Error),
+    /// Something that will happen again, like a missing file.
+    Fatal(Error),
+}
+
+impl Client {
+    /// Get the settings from the config file.
+    pub fn from_config() -> Client {
+        Client {
+            timeout: Duration::from_secs(CFG.timeout.unwrap_or(30)),
+            retries: CFG.retries.unwrap_or(3),
+            backoff: Duration::from_secs(1),
+            max_redirects: 10,
+            proxy: CFG.proxy.clone(),
+        }
+    }
+
+    /// Download a url to a file. The response is streamed to <file>.part,
+    /// which is resumed with a Range request if it is left over from an
+    /// interrupted download, and only moved into place once it is complete. If
+    /// a checksum is given, the file is hashed as it is written and rejected if
+    /// it doesn't match.
+    pub fn download(
+        &self,
+        url: &String,
+        filename: &String,
+        checksum: Option<&String>,
+        bar: &ProgressBar,
+        bar_style: &ProgressStyle
+    ) -> Result<()> {
+        let part = format!("{filename}.part");
+        let expected = match checksum {
+            Some(x) => Some(checksum::parse(x).context(format!("Invalid checksum {x}"))?),
+            None => None,
+        };
+
+        let algorithm = expected.as_ref().map(|x| x.0).unwrap_or(Algorithm::Blake3);
+
+        let mut attempt = 0;
+        let hash = loop {
+            match self.try_download(url, &part, algorithm, bar, bar_style) {
+                Ok(x) => break x,
+                Err(Failure::Transient(_)) if attempt < self.retries => {
+                    // Wait a bit longer each time, in case the server is
+                    // overloaded. Anything already downloaded is kept.
+                    thread::sleep(self.backoff * 2u32.pow(attempt as u32));
+                    attempt += 1;
+                },
+                Err(x) => {
+                    // Keep the part file only if there is something to resume.
+                    if fs::metadata(&part).map(|x| x.len() == 0).unwrap_or(false) {
+                        let _ = fs::remove_file(&part);
+                    }
+
+                    return Err(match x {
+                        Failure::Transient(e) => e.context(format!("Giving up on {url} after {} attempt(s)", attempt + 1)),
+                        Failure::Fatal(e) => e,
+                    });
+                },
+            }
+        };
+
+        if let Some((_, sum)) = expected {
+            if hash != sum {
+                let _ = fs::remove_file(&part);
+                bail!("Checksum mismatch for downloaded file {filename}");
+            }
+        }
+
+        fs::rename(&part, filename).context(format!("Couldn't move {part} to {filename}"))?;
+        Ok(())
+    }
+
+    /// Try to download a url to a part file once, following redirects. Returns
+    /// the hash of the whole file.
+    fn try_download(
+        &self,
+        url: &String,
+        part: &String,
+        algorithm: Algorithm,
+        bar: &ProgressBar,
+        bar_style: &ProgressStyle
+    ) -> Result<String, Failure> {
+        let fatal = |e: Error| Failure::Fatal(e);
+        let start = url;
+        let mut url = url.clone();
+        let mut redirects = 0;
+        let mut resume = true;
+
+        loop {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(part)
+                .context(format!("Couldn't create file {part}"))
+                .map_err(fatal)?;
+
+            if !resume {
+                file.set_len(0).context(format!("Couldn't truncate file {part}")).map_err(fatal)?;
+            }
+
+            let offset = file.metadata().context(format!("Couldn't read file {part}")).map_err(fatal)?.len();
+            let mut req = self.agent(&url).map_err(fatal)?.get(&url);
+            if offset > 0 {
+                req = req.set("Range", &format!("bytes={offset}-"));
+            }
+
+            let res = match req.call() {
+                Ok(x) => x,
+                Err(ureq::Error::Status(code, res)) => {
+                    if offset > 0 && code == 416 {
+                        // The part file doesn't fit the file on the server
+                        // (it may already be complete), so start over.
+                        resume = false;
+                        continue;
+                    }
+
+                    let e = anyhow!("Failed to download source {url} ({code} {})", res.status_text());
+
+                    // Server errors, timeouts and rate limits may clear up.
+                    return Err(match code {
+                        408 | 429 | 500..=599 => Failure::Transient(e),
+                        _ => Failure::Fatal(e),
+                    });
+                },
+                Err(ureq::Error::Transport(e)) => {
+                    let reason = match (std::error::Error::source(&e), e.message()) {
+                        (Some(x), _) => x.to_string(),
+                        (None, Some(x)) => format!("{}: {x}", e.kind()),
+                        (None, None) => e.kind().to_string(),
+                    };
+
+                    return Err(Failure::Transient(anyhow!("Couldn't connect to {url} ({reason})")));
+                },
+            };
+
+            let code = res.status();
+            if (300..400).contains(&code) {
+                // The request returned a redirect, get the actual file
+                // location, which may be relative to this url.
+                redirects += 1;
+                if redirects > self.max_redirects {
+                    return Err(fatal(anyhow!("Too many redirects from {start} (more than {})", self.max_redirects)));
+                }
+
+                let location = res.header("Location")
+                    .context(format!("Redirect from {url} has no location"))
+                    .map_err(fatal)?;
+
+                url = resolve_url(&url, location).map_err(fatal)?;
+                continue;
+            }
+
+            // If the server sent the whole file instead of the part we asked
+            // for, write it from the start.
+            let offset = if code == 206 { offset } else { 0 };
+            if offset == 0 {
+                file.set_len(0).context(format!("Couldn't truncate file {part}")).map_err(fatal)?;
+            }
+
+            // Hash what was already downloaded, so that the checksum covers
+            // the whole file.
+            let mut hasher = Hasher::new(algorithm);
+            let mut old = File::open(part).context(format!("Couldn't read file {part}")).map_err(fatal)?;
+            io::copy(&mut old, &mut hasher).context(format!("Couldn't read file {part}")).map_err(fatal)?;
+
+            let len = res.header("Content-Length").and_then(|x| x.parse::<u64>().ok());
+            bar.set_position(0);
+            util::inc_bar(bar, offset, len.map(|x| x + offset).unwrap_or(0) as usize, bar_style);
+
+            // Stream the body to the part file. Connections that are closed
+            // early leave the rest of the file to be resumed.
+            let mut reader = res.into_reader();
+            let mut buf = vec![0; 64 * 1024];
+            let mut written = 0;
+            loop {
+                let n = match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(Failure::Transient(anyhow!("Download of {url} was interrupted: {e}"))),
+                };
+
+                file.write_all(&buf[..n]).context(format!("Couldn't write to {part}")).map_err(fatal)?;
+                hasher.update(&buf[..n]);
+                written += n as u64;
+                util::inc_bar(bar, n as u64, len.map(|x| x + offset).unwrap_or(0) as usize, bar_style);
+            }
+
+            if let Some(x) = len {
+                if written < x {
+                    return Err(Failure::Transient(anyhow!(
+                        "Download of {url} was interrupted ({} of {} bytes)",
+                        offset + written,
+                        offset + x,
+                    )));
+                }
+            }
+
+            return Ok(hasher.finalize());
+        }
+    }
+
+    /// Make an agent for a request to a url, which handles redirects itself
+    /// and goes through a proxy if there is one for the url.
+    fn agent(&self, url: &String) -> Result<ureq::Agent> {
+        let mut agent = ureq::AgentBuilder::new()
+            .timeout_connect(self.timeout)
+            .timeout_read(self.timeout)
+            .timeout_write(self.timeout)
+            .redirects(0)
+            .user_agent(&format!("moss/{}", env!("CARGO_PKG_VERSION")));
+
+        if let Some(proxy) = self.proxy_for(url)? {
+            let proxy = ureq::Proxy::new(&proxy).context(format!("Invalid proxy {proxy}"))?;
+            agent = agent.proxy(proxy);
+        }
+
+        Ok(agent.build())
+    }
+
+    /// Get the proxy to use for a url.
+    fn proxy_for(&self, url: &String) -> Result<Option<String>> {
+        if let Some(x) = &self.proxy {
+            return Ok(Some(x.clone()));
+        }
+
+        let parsed = Url::parse(url).context(format!("Invalid url {url}"))?;
+        let host = parsed.host_str().unwrap_or("");
+
+        // no_proxy is a list of hosts (or domains, e.g. '.example.com') that
+        // are reached directly, or '*' for all of them.
+        let no_proxy = env_var("no_proxy").unwrap_or_default();
+        for entry in no_proxy.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+            let domain = entry.trim_start_matches('.');
+            if entry == "*" || host == domain || host.ends_with(&format!(".{domain}")) {
+                return Ok(None);
+            }
+        }
+
+        let var = if parsed.scheme() == "https" { "https_proxy" } else { "http_proxy" };
+        Ok(env_var(var).or_else(|| env_var("all_proxy")))
+    }
+}
+
+/// Get an environment variable, in lower case or upper case, if it is set and
+/// isn't empty.
+fn env_var(name: &str) -> Option<String> {
+    env::var(name)
+        .or_else(|_| env::var(name.to_uppercase()))
+        .ok()
+        .filter(|x| !x.is_empty())
+}
+
+/// Resolve a url from a Location header, which may be relative, against the url
+/// of the request.
+pub fn resolve_url(base: &String, location: &str) -> Result<String> {
+    let base = Url::parse(base).context(format!("Invalid url {base}"))?;
+    let url = base.join(location).context(format!("Invalid redirect location {location}"))?;
+    Ok(url.to_string())
+}
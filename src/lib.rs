@@ -1,23 +1,40 @@
 //! This module contains the main commands that can be directly called by the
 //! user through command line arguments.
 
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fs::{self, File, OpenOptions};
 use std::io::Write;
 use std::os::unix::fs::OpenOptionsExt;
 use std::process::{self, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 
 use anyhow::{bail, Context, Result};
 use glob::glob;
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
 use lazy_static::lazy_static;
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use nix::unistd::{Uid, User};
+
+use checksum::Algorithm;
+use plan::Plan;
 
 pub mod args;
 pub mod actions;
 pub mod config;
 pub mod bars;
+pub mod checksum;
+pub mod extract;
+pub mod gc;
+pub mod http;
 pub mod log;
+pub mod mirror;
+pub mod plan;
+pub mod sandbox;
+pub mod store;
+pub mod triggers;
 pub mod util;
 
 lazy_static! {
@@ -35,7 +52,105 @@ lazy_static! {
 
     pub static ref ARC_PATH: Vec<String> = CFG.path.clone();
 
+    /// The user to build packages as, if moss is running as root and
+    /// build_user is set. Packages are still installed as root. A missing
+    /// user is only an error once something needs to be built (see
+    /// build_user).
+    pub static ref BUILD_USER: Result<Option<User>, String> = match &CFG.build_user {
+        Some(name) if Uid::effective().is_root() => match User::from_name(name) {
+            Ok(Some(x)) => Ok(Some(x)),
+            _ => Err(format!("Couldn't find build user {name}")),
+        },
+        _ => Ok(None),
+    };
+
     pub static ref CACHE: String = CFG.cache_dir.clone().unwrap_or(format!("{}/.cache/moss", *HOME));
+
+    /// Where downloaded sources are kept under their checksums (see store.rs).
+    pub static ref STORE: String = CFG.store_dir.clone().unwrap_or(format!("{}/store", *CACHE));
+
+    /// Where installed packages are tracked, along with their hooks and the
+    /// alternatives store.
+    pub static ref DB: String = if user_mode() {
+        let data = env::var("XDG_DATA_HOME").ok().filter(|x| !x.is_empty());
+        format!("{}/moss", data.unwrap_or(format!("{}/.local/share", *HOME)))
+    } else {
+        "/var/cache/moss".into()
+    };
+
+    /// The prefix that build scripts should install to, given as $3.
+    pub static ref PREFIX: String = if user_mode() {
+        CFG.user_prefix.clone().unwrap_or(format!("{}/.local", *HOME))
+    } else {
+        "/usr".into()
+    };
+}
+
+/// Whether moss is installing packages for the current user only. This must be
+/// set before DB or PREFIX are first used.
+static USER_MODE: AtomicBool = AtomicBool::new(false);
+
+pub fn set_user_mode(user: bool) {
+    USER_MODE.store(user, Ordering::Relaxed);
+}
+
+pub fn user_mode() -> bool {
+    USER_MODE.load(Ordering::Relaxed)
+}
+
+/// Get the user to build packages as, if any, failing if build_user is set to
+/// a user that doesn't exist.
+pub fn build_user() -> Result<Option<&'static User>> {
+    match &*BUILD_USER {
+        Ok(x) => Ok(x.as_ref()),
+        Err(e) => bail!("{e}"),
+    }
+}
+
+/// Get the directory that built package tarballs are kept in, under a cache
+/// directory. Packages built for user mode have the prefix and the package
+/// database baked in, so they are kept apart from system builds, and from user
+/// builds with a different prefix.
+pub fn bin_dir(cache: &str) -> String {
+    if user_mode() {
+        let hash = blake3::hash(format!("{}\n{}", *PREFIX, *DB).as_bytes()).to_string();
+        format!("{cache}/bin/user-{}", &hash[..12])
+    } else {
+        format!("{cache}/bin")
+    }
+}
+
+/// Lock the cache, so that gc doesn't remove build dirs and partial downloads
+/// from under a running build or download. Commands that use the cache hold a
+/// shared lock until they exit, while gc takes an exclusive one. If wait is
+/// false and the lock is busy, returns None.
+pub fn lock_cache(exclusive: bool, wait: bool) -> Result<Option<Flock<File>>> {
+    let path = format!("{}/lock", *CACHE);
+    let file = File::create(&path).context(format!("Couldn't open lock file {path}"))?;
+    let arg = match (exclusive, wait) {
+        (true, true) => FlockArg::LockExclusive,
+        (true, false) => FlockArg::LockExclusiveNonblock,
+        (false, true) => FlockArg::LockShared,
+        (false, false) => FlockArg::LockSharedNonblock,
+    };
+
+    match Flock::lock(file, arg) {
+        Ok(x) => Ok(Some(x)),
+        Err((_, Errno::EWOULDBLOCK)) => Ok(None),
+        Err((_, e)) => Err(e).context(format!("Couldn't lock {path}")),
+    }
+}
+
+/// Whether to work only with sources that are already in the cache, which is
+/// enabled with the '--offline' option.
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+pub fn set_offline(offline: bool) {
+    OFFLINE.store(offline, Ordering::Relaxed);
+}
+
+pub fn offline() -> bool {
+    OFFLINE.load(Ordering::Relaxed)
 }
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -68,24 +183,36 @@ pub fn print_help(code: i32, msg: String) -> ! {
     eprintln!("\x1b[35m/ /\\/\\ \\ \x1b[36m(_)\x1b[90m \\__ \\__ \\\x1b[0m");
     eprintln!("\x1b[35m\\/    \\/\x1b[90m\\\x1b[0m\x1b[33m___\x1b[90m/|\x1b[0m\x1b[33m___\x1b[90m/\x1b[0m\x1b[33m___\x1b[90m/");
     eprintln!("\x1b[0m");
-    eprintln!("Usage: \x1b[33mmoss\x1b[0m [s/v/y][b/c/d/f/h/i/l/n/p/r/s/u/v] [pkg]...");
-    log::info_ident("b / build     Build packages");
-    log::info_ident("c / checksum  Generate checksums");
-    log::info_ident("d / download  Download sources");
-    log::info_ident("f / find      Fuzzy search for a package");
-    log::info_ident("h / help      Print this help");
-    log::info_ident("i / install   Install built packages");
-    log::info_ident("l / list      List installed packages");
-    log::info_ident("n / new       Create a blank package");
-    info_ident_fmt!("p / purge     Purge the package cache ({cache_display})");
-    log::info_ident("r / remove    Remove packages");
-    log::info_ident("s / sync      Sync remote repositories");
-    log::info_ident("u / upgrade   Upgrade all packages");
-    log::info_ident("v / version   Print version");
+    eprintln!("Usage: \x1b[33mmoss\x1b[0m [s/v/y][a/b/c/d/f/g/h/i/l/m/n/p/r/s/u/v] [option]... [pkg]...");
+    log::info_ident("a / alternatives  List or swap alternatives");
+    log::info_ident("b / build         Build packages");
+    log::info_ident("c / checksum      Update checksums in package.toml");
+    log::info_ident("d / download      Download sources");
+    log::info_ident("f / find          Fuzzy search for a package");
+    log::info_ident("g / gc            Remove unused files from the package cache");
+    log::info_ident("h / help          Print this help");
+    log::info_ident("i / install       Install built packages");
+    log::info_ident("l / list          List installed packages");
+    log::info_ident("m / mirror        Download all sources in the repositories to a mirror dir");
+    log::info_ident("n / new           Create a blank package");
+    info_ident_fmt!("p / purge         Purge the package cache ({cache_display})");
+    log::info_ident("r / remove        Remove packages");
+    log::info_ident("s / sync          Sync remote repositories");
+    log::info_ident("u / upgrade       Upgrade all packages");
+    log::info_ident("v / version       Print version");
     eprintln!("Flags:");
     log::info_ident("s  Sync remote repositories");
     log::info_ident("v  Enable verbose builds");
     log::info_ident("y  Skip confirmation prompts");
+    eprintln!("Options:");
+    log::info_ident("--algorithm=<algo>   Checksum algorithm to write (b3, sha256, sha512) (checksum)");
+    log::info_ident("--check              Only report checksum mismatches (checksum)");
+    log::info_ident("--conflict=<policy>  Handle file conflicts (prompt, overwrite, keep-existing, abort)");
+    log::info_ident("--deps               Also download sources for dependencies (download)");
+    log::info_ident("--dry-run            Only list what would be removed (gc)");
+    log::info_ident("--offline            Only use sources that are already downloaded (build, download, mirror)");
+    log::info_ident("--older-than=<days>  Only remove files not modified in this many days (gc)");
+    log::info_ident("--user               Install packages for the current user only");
     eprintln!("\nCreated by AVS Origami\n");
     process::exit(code)
 }
@@ -133,41 +260,279 @@ pub fn purge_cache() -> Result<()> {
     Ok(())
 }
 
+/// Remove files from the cache that are no longer needed, unlike purge_cache
+/// which removes everything. See gc.rs for what counts as unused. With
+/// '--dry-run', only list what would be removed.
+pub fn gc(args: &args::Cmd) -> Result<()> {
+    // Other moss processes hold a shared lock on the cache while they use it.
+    let lock = lock_cache(true, false)?;
+    if lock.is_none() {
+        log::warn("The cache is in use, leaving build dirs, tmp dirs and partial downloads alone");
+    }
+
+    let min_age = args.older_than.map(|x| Duration::from_secs(x.saturating_mul(24 * 60 * 60)));
+    let unused = gc::unused(&gc::Layout::from_config(), min_age, lock.is_none())?;
+    if unused.is_empty() {
+        log::info("Nothing to remove from the cache");
+        return Ok(());
+    }
+
+    let verb = if args.dry_run { "Would remove" } else { "Removing" };
+    info_fmt!("{verb} {} unused file(s) from {}", unused.len(), *CACHE);
+
+    let mut freed = 0;
+    for entry in &unused {
+        let path = entry.path.strip_prefix(&format!("{}/", *CACHE)).unwrap_or(&entry.path);
+        info_ident_fmt!("{path} \x1b[36m({}, {})\x1b[0m", HumanBytes(entry.size), entry.reason);
+
+        if !args.dry_run {
+            let res = match fs::symlink_metadata(&entry.path) {
+                Ok(x) if x.is_dir() => fs::remove_dir_all(&entry.path),
+                _ => fs::remove_file(&entry.path),
+            };
+
+            res.context(format!("Couldn't remove {}", entry.path))?;
+        }
+
+        freed += entry.size;
+    }
+
+    if args.dry_run {
+        info_fmt!("Would free {}", HumanBytes(freed));
+    } else {
+        info_fmt!("Freed {}", HumanBytes(freed));
+    }
+
+    Ok(())
+}
+
 /// List installed packages, one per line.
 pub fn list() -> Result<()> {
-    let installed = glob::glob("/var/cache/moss/installed/*")?;
+    let installed = glob::glob(&format!("{}/installed/*", *DB))?;
     for pkg in installed {
-        info_fmt!("{}", &pkg?.display().to_string().split('/').last().unwrap());
+        let pkg = pkg?.display().to_string().split('/').next_back().unwrap().to_string();
+
+        // Show the commits that packages with git sources were built from.
+        let name = pkg.split('@').next().unwrap();
+        match fs::read_to_string(format!("{}/revisions/{name}", *DB)) {
+            Ok(x) => {
+                let commits: Vec<&str> = x.lines().filter_map(|x| x.split(' ').next_back()).map(|x| &x[..x.len().min(10)]).collect();
+                info_fmt!("{pkg} \x1b[36m(git {})\x1b[0m", commits.join(", "));
+            },
+            Err(_) => info_fmt!("{pkg}"),
+        }
     }
 
     Ok(())
 }
 
+/// With no arguments, list the files in the alternatives store. Otherwise,
+/// swap which package provides a path: the file currently at the path is moved
+/// into the store under the package that owns it, the chosen package's copy
+/// takes its place, and both manifests are updated.
+pub fn alternatives(choice: &Option<(String, String)>) -> Result<()> {
+    let Some((pack, path)) = choice else {
+        for entry in glob(&format!("{}/choices/*", *DB))? {
+            // Skip leftovers that are no longer part of any package.
+            let entry = entry?.display().to_string();
+            if actions::is_tracked(&entry)?.is_none() {
+                continue;
+            }
+
+            if let Some((pack, path)) = actions::parse_choice(entry.split('/').next_back().unwrap()) {
+                info_fmt!("\x1b[36m{pack}\x1b[0m {path}");
+            }
+        }
+
+        return Ok(());
+    };
+
+    let choice_path = actions::choice_path(pack, path);
+    if fs::symlink_metadata(&choice_path).is_err() {
+        bail!("Package {pack} has no alternative for {path}");
+    }
+
+    if !actions::is_installed(pack, &"*".into())? {
+        bail!("Package {pack} is not installed");
+    }
+
+    if actions::is_tracked(path)?.is_none() && fs::symlink_metadata(path).is_ok() {
+        bail!("File {path} exists but is not tracked by any package");
+    }
+
+    // Moving files around needs root, so hand it off as a plan.
+    plan::execute(&Plan {
+        cache: CACHE.clone(),
+        swap: Some((pack.clone(), path.clone())),
+        ..Default::default()
+    })
+}
+
 /// Download the source files for some packages, even if they already exist.
-pub fn download(packs: &Vec<String>) -> Result<()> {
+/// With '--deps', also download the sources of every dependency and make
+/// dependency that isn't installed yet, if they don't already exist.
+pub fn download(packs: &Vec<String>, args: &args::Cmd) -> Result<()> {
+    let pack_toml = actions::parse_package(packs)?;
+    let (mut dep_toml, mut mkdep_toml) = if args.deps {
+        actions::resolve_deps(&pack_toml, 1, &mut HashSet::new())?
+    } else {
+        (vec![], vec![])
+    };
+
+    // Packages can be both a dependency and a make dependency.
+    dep_toml.append(&mut mkdep_toml);
+    let mut seen = HashSet::new();
+    dep_toml.retain(|x| !packs.contains(&x.name) && seen.insert(x.name.clone()));
+
+    let dep_names: Vec<String> = dep_toml.iter().map(|x| x.name.clone()).collect();
+    let pad = packs.iter().chain(&dep_names).map(|x| x.len()).max().unwrap_or(0);
+
     log::info("Downloading sources");
-    actions::download_all(packs, None, true, None)?;
+    actions::download_all(packs, Some(pack_toml), true, Some(pad))?;
+    if !dep_toml.is_empty() {
+        info_fmt!("Downloading sources for {} dependencies", dep_toml.len());
+        actions::download_all(&dep_names, Some(dep_toml), false, Some(pad))?;
+    }
+
     Ok(())
 }
 
-/// Generate checksums for the package defined by the current directory. Will
-/// download the source files even if they already exist.
-pub fn generate_checksums() -> Result<()> {
+/// Generate checksums for some packages, and write them to each package.toml,
+/// keeping the rest of the file as it is. Will download the source files even
+/// if they already exist. With no packages given, use the package in the
+/// current directory, or every package in it if it is a repository.
+///
+/// Each checksum is written with the algorithm given with '--algorithm', or
+/// the one that it was already written with, or blake3 by default. With
+/// '--check', nothing is downloaded again or written, and any checksums that
+/// don't match are reported instead.
+pub fn generate_checksums(packs: &[String], args: &args::Cmd) -> Result<()> {
+    let packs = if !packs.is_empty() {
+        packs.to_vec()
+    } else if fs::metadata("package.toml").is_ok() {
+        vec![".".into()]
+    } else {
+        let mut res = vec![];
+        for file in glob("*/package.toml")? {
+            res.push(file?.parent().unwrap().display().to_string());
+        }
+
+        if res.is_empty() {
+            bail!("No packages found in the current directory");
+        }
+
+        res
+    };
+
     // Download the source files and get the path to each one.
-    let pack = actions::download_all(&vec![".".into()], None, true, None)?;
-    let mut hashes = vec![];
-    for file in &pack[0].sources {
-        // Remove any prefixes from the file name.
-        let file = if &file[3..4] == "+" { &file[4..] } else { &file[..] };
-        // Calculate the b3sum for the file and add it to the list of hashes.
-        let data: Vec<u8> = fs::read(file).context("Failed to read source file")?;
-        let hash = blake3::hash(&data);
-        hashes.push(hash.to_string());
+    log::info("Downloading sources");
+    let pack_toml = actions::download_all(&packs, None, !args.check, None)?;
+    eprintln!();
+
+    let mut mismatched = vec![];
+    for toml in &pack_toml {
+        let mut hashes = vec![];
+        for (i, file) in toml.sources.iter().enumerate() {
+            // Keep following the branch of a git source if it isn't pinned.
+            let old = toml.meta.checksums.get(i);
+            let skip = old.map(|x| x == "SKIP").unwrap_or(false);
+            if skip && actions::parse_git_source(file).is_some() {
+                hashes.push("SKIP".into());
+                continue;
+            }
+
+            let old_algorithm = old.and_then(|x| checksum::parse(x).ok()).map(|(x, _)| x);
+            let algorithm = match args.algorithm {
+                Some(x) if !args.check => x,
+                _ => old_algorithm.unwrap_or(Algorithm::Blake3),
+            };
+
+            hashes.push(actions::source_checksum(file, algorithm)?);
+        }
+
+        let path = format!("{}/package.toml", toml.dir);
+        if !args.check {
+            actions::write_checksums(&path, &hashes)?;
+            info_fmt!("\x1b[36m{}\x1b[0m Updated checksums in {path}", toml.name);
+            continue;
+        }
+
+        let mut ok = hashes.len() == toml.meta.checksums.len();
+        for (i, (hash, sum)) in hashes.iter().zip(&toml.meta.checksums).enumerate() {
+            // 'b3:<hex>' and '<hex>' are the same checksum.
+            if hash != sum && checksum::parse(hash).ok() != checksum::parse(sum).ok() {
+                let file = toml.sources[i].split('/').next_back().unwrap();
+                log::warn(&format!("{}: checksum mismatch for {file} (expected {sum}, got {hash})", toml.name));
+                ok = false;
+            }
+        }
+
+        if hashes.len() != toml.meta.checksums.len() {
+            log::warn(&format!("{}: expected {} checksums, found {}", toml.name, hashes.len(), toml.meta.checksums.len()));
+        }
+
+        if !ok {
+            mismatched.push(toml.name.clone());
+        }
     }
 
-    // Pretty-print the hashes, conveniently putting them in TOML format.
-    eprintln!("Add the following to package.toml under [meta]:");
-    println!("checksums = {hashes:#?}");
+    if !mismatched.is_empty() {
+        bail!("Checksums don't match for {} package(s): {}", mismatched.len(), mismatched.join(", "));
+    }
+
+    if args.check {
+        log::info("All checksums match");
+    }
+
+    Ok(())
+}
+
+/// Download and verify every remote source of every package in some
+/// repositories (all of the configured ones by default) into a directory that
+/// can be served as a mirror (see mirror.rs). Sources that are already there
+/// are skipped, and packages with sources that are broken are listed at the
+/// end.
+pub fn mirror(dir: &str, repos: &[String]) -> Result<()> {
+    let repos = if !repos.is_empty() { repos.to_vec() } else { ARC_PATH.clone() };
+    fs::create_dir_all(dir).context(format!("Couldn't create directory {dir}"))?;
+
+    info_fmt!("Mirroring sources from {} repositories to {dir}", repos.len());
+    let mut plan = mirror::plan(dir, &repos)?;
+
+    if offline() {
+        for download in plan.downloads.drain(..) {
+            plan.broken.push(format!("{}: {} isn't in the source store", download.name, download.url));
+        }
+    }
+
+    for target in &plan.replaced {
+        log::warn(&format!("{target} doesn't match its checksum, replacing it"));
+    }
+
+    // Downloads that fail are reported by fetch_each.
+    for problem in &plan.broken {
+        log::warn(problem);
+    }
+
+    let mut broken: Vec<String> = plan.broken.iter().map(|x| x.split(':').next().unwrap().to_string()).collect();
+    let pad = plan.downloads.iter().map(|x| x.name.len()).max().unwrap_or(0);
+    let failed = actions::fetch_each(&plan.downloads, pad);
+    for (i, _) in &failed {
+        broken.push(plan.downloads[*i].name.clone());
+    }
+
+    info_fmt!(
+        "Mirrored {} source(s) ({} already present, {} copied from the source store)",
+        plan.present + plan.copied + plan.downloads.len() - failed.len(),
+        plan.present,
+        plan.copied,
+    );
+
+    if !broken.is_empty() {
+        broken.sort();
+        broken.dedup();
+        bail!("{} package(s) have broken sources: {}", broken.len(), broken.join(", "));
+    }
 
     Ok(())
 }
@@ -211,7 +576,7 @@ pub fn sync() -> Result<()> {
 /// Perform a full system upgrade (update packages that have available updates).
 pub fn upgrade(args: &args::Cmd) -> Result<()> {
     log::info("Performing full system upgrade.");
-    let installed = glob::glob("/var/cache/moss/installed/*")?;
+    let installed = glob::glob(&format!("{}/installed/*", *DB))?;
     let mut packs = vec![];
 
     for pkg in installed {
@@ -280,13 +645,29 @@ pub fn search(name: String) -> Result<()> {
 /// 7. Prompt to install remaining explicit packages.
 pub fn build(packs: &Vec<String>, args: &args::Cmd) -> Result<()> {
     // Output package summary.
-    let (pack_toml, dep_toml, dep_names, mkdep_toml, mkdep_names, real_pad) = actions::summary(packs, args, "Building")?;
+    let (pack_toml, dep_toml, _, mkdep_toml, _, real_pad) = actions::summary(packs, args, "Building")?;
 
-    // Download all source files.
+    // Download all source files together, so that they are downloaded in
+    // parallel, and so that every missing file is listed when offline. A
+    // package can be in more than one of the lists, but its sources are only
+    // queued once.
     log::info("Downloading sources");
-    let pack_toml = actions::download_all(packs, Some(pack_toml), false, Some(real_pad))?;
-    let dep_toml = actions::download_all(&dep_names, Some(dep_toml), false, Some(real_pad))?;
-    let mkdep_toml = actions::download_all(&mkdep_names, Some(mkdep_toml), false, Some(real_pad))?;
+    let mut seen = HashSet::new();
+    let all_toml = pack_toml.iter().chain(&dep_toml).chain(&mkdep_toml)
+        .filter(|x| seen.insert(x.name.clone()))
+        .cloned()
+        .collect();
+
+    let sources: HashMap<String, Vec<String>> = actions::download_all(packs, Some(all_toml), false, Some(real_pad))?
+        .into_iter()
+        .map(|x| (x.name, x.sources))
+        .collect();
+
+    let (mut pack_toml, mut dep_toml, mut mkdep_toml) = (pack_toml, dep_toml, mkdep_toml);
+    for pack in pack_toml.iter_mut().chain(&mut dep_toml).chain(&mut mkdep_toml) {
+        pack.sources = sources[&pack.name].clone();
+    }
+
     eprintln!();
 
     // Verify checksums for all the source files.
@@ -322,7 +703,7 @@ pub fn build(packs: &Vec<String>, args: &args::Cmd) -> Result<()> {
             )?;
 
             info_fmt!("Installing layer {} make dependencies", mkdep_toml[idx.0].depth);
-            actions::install_all(&mkdep_toml[idx.0..idx.1].to_vec())?;
+            actions::install_all(&mkdep_toml[idx.0..idx.1].to_vec(), args)?;
             eprintln!();
         }
     }
@@ -352,11 +733,11 @@ pub fn build(packs: &Vec<String>, args: &args::Cmd) -> Result<()> {
                 args,
             )?;
 
+            // The whole layer is installed together, so root is only needed
+            // once per layer.
             info_fmt!("Installing layer {} dependencies", dep_toml[idx.0].depth);
-            for inst in &dep_toml[idx.0..idx.1] {
-                actions::install_all(&vec![inst.clone()])?;
-                eprintln!();
-            }
+            actions::install_all(&dep_toml[idx.0..idx.1].to_vec(), args)?;
+            eprintln!();
         }
     }
 
@@ -367,7 +748,7 @@ pub fn build(packs: &Vec<String>, args: &args::Cmd) -> Result<()> {
     // packages that were just build.
     log::info("Installing built packages.");
     if !args.yes { log::prompt(); }
-    actions::install_all(&pack_toml)?;
+    actions::install_all(&pack_toml, args)?;
 
     Ok(())
 }
@@ -376,7 +757,7 @@ pub fn build(packs: &Vec<String>, args: &args::Cmd) -> Result<()> {
 /// cache directory.
 pub fn install(packs: &Vec<String>, args: &args::Cmd) -> Result<()> {
     let (pack_toml, _, _, _, _, _) = actions::summary(packs, args, "Installing")?;
-    actions::install_all(&pack_toml)?;
+    actions::install_all(&pack_toml, args)?;
     Ok(())
 }
 
@@ -392,8 +773,8 @@ pub fn remove(packs: &Vec<String>, args: &args::Cmd) -> Result<()> {
         }
 
         // Read the package manifest.
-        let mut manifest_glob = glob(&format!("/var/cache/moss/installed/{pack}@*"))
-            .context(format!("Error constructing glob /var/cache/moss/installed/{pack}@*"))?;
+        let mut manifest_glob = glob(&format!("{}/installed/{pack}@*", *DB))
+            .context(format!("Error constructing glob {}/installed/{pack}@*", *DB))?;
 
         let manifest_path = manifest_glob.next().unwrap().context("Couldn't get manifest path")?;
 
@@ -405,39 +786,18 @@ pub fn remove(packs: &Vec<String>, args: &args::Cmd) -> Result<()> {
             let real_name = real_pack.split('@').next().unwrap();
             bail!("Package '{pack}' is provided by '{real_pack}'; to remove it, remove '{real_name}' instead");
         }
-
-        // Since the manifest was generated using a glob, we iterate through
-        // the lines in reverse to remove the deepest files first.
-        for file in manifest.lines().rev() {
-            if file == "/var/cache/moss/installed" {
-                continue;
-            }
-
-            let _ = Command::new("rmdir")
-                .arg(file)
-                .stdout(Stdio::null())
-                .stderr(Stdio::null())
-                .status();
-
-            if let Some(_) = actions::is_tracked(&file.into())? {
-                if !fs::symlink_metadata(file)?.file_type().is_symlink() {
-                    let _ = Command::new("rm")
-                        .arg(file)
-                        .stdout(Stdio::null())
-                        .stderr(Stdio::null())
-                        .status();
-                }
-            } else {
-                let _ = Command::new("rm")
-                    .arg(file)
-                    .stdout(Stdio::null())
-                    .stderr(Stdio::null())
-                    .status();
-            }
-        }
-
-        info_fmt!("{pack} Successfully uninstalled package");
     }
 
-    Ok(())
+    // Removing files needs root, so hand it off as a plan.
+    plan::execute(&Plan {
+        cache: CACHE.clone(),
+        remove: packs.clone(),
+        ..Default::default()
+    })
+}
+
+/// Apply a transaction plan written by another moss process. This is used
+/// internally to run the privileged part of a transaction as root.
+pub fn apply(plan_file: &String) -> Result<()> {
+    plan::apply_file(plan_file)
 }
@@ -7,6 +7,12 @@ use moss::log;
 use moss::args::{self, Op};
 
 fn main() {
+    // Collect and parse CLI arguments.
+    let mut cli_args: Vec<String> = env::args().collect();
+    let parsed = args::parse(&mut cli_args);
+    moss::set_user_mode(parsed.user);
+    moss::set_offline(parsed.offline);
+
     // Create the cache directory, if it doesn't exist. This is where source
     // files, builds, and logs are stored.
     match fs::create_dir_all((*moss::CACHE).clone())
@@ -20,8 +26,9 @@ fn main() {
 
     // Create the package installation cache, if it doesn't exist. This
     // directory is where all package files are tracked by the package manager.
-    match fs::create_dir_all("/var/cache/moss/installed")
-        .context("Failed to create install cache /var/cache/moss/installed")
+    let installed = format!("{}/installed", *moss::DB);
+    match fs::create_dir_all(&installed)
+        .context(format!("Failed to create install cache {installed}"))
     {
         Ok(_) => (),
         Err(e) => {
@@ -29,9 +36,15 @@ fn main() {
         }
     }
 
-    // Collect and parse CLI arguments.
-    let mut cli_args: Vec<String> = env::args().collect();
-    let parsed = args::parse(&mut cli_args);
+    // Keep gc from removing files that this process is using (see lock_cache).
+    let _lock = match parsed.kind {
+        Op::Build(_) | Op::Checksum(_) | Op::Download(_) | Op::Install(_)
+        | Op::Mirror(..) | Op::Remove(_) | Op::Upgrade => match moss::lock_cache(false, true) {
+            Ok(x) => x,
+            Err(e) => log::die(&format!("{:#}", &e)),
+        },
+        _ => None,
+    };
 
     if parsed.sync {
         match moss::sync() {
@@ -46,13 +59,17 @@ fn main() {
     // the result. All commands return a Result<()> which allows for nice
     // error handling.
     let status = match parsed.kind {
+        Op::Alternatives(ref x) => moss::alternatives(x),
+        Op::Apply(ref x) => moss::apply(x),
         Op::Build(ref x) => moss::build(x, &parsed),
-        Op::Checksum => moss::generate_checksums(),
+        Op::Checksum(ref x) => moss::generate_checksums(x, &parsed),
         Op::Die(x, msg) => moss::print_help(x, msg),
-        Op::Download(ref x) => moss::download(x),
+        Op::Download(ref x) => moss::download(x, &parsed),
         Op::Find(x) => moss::search(x),
+        Op::Gc => moss::gc(&parsed),
         Op::Install(ref x) => moss::install(x, &parsed),
         Op::List => moss::list(),
+        Op::Mirror(ref x, ref y) => moss::mirror(x, y),
         Op::New(x) => moss::new(x),
         Op::Purge => moss::purge_cache(),
         Op::Remove(ref x) => moss::remove(x, &parsed),
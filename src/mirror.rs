@@ -0,0 +1,172 @@
+//! This module contains logic to fill a source mirror from the repositories.
+//! Sources are laid out as <dir>/<host>/<path>, so that serving the dir at
+//! e.g. http://mirror.example.com/ and adding
+//!
+//! [mirrors]
+//! "https://" = ["http://mirror.example.com/"]
+//! "http://" = ["http://mirror.example.com/"]
+//!
+//! to moss.toml makes every source be looked for on the mirror first.
+
+use std::collections::HashMap;
+use std::fs;
+
+use anyhow::{bail, Context, Result};
+use glob::glob;
+use percent_encoding::percent_decode_str;
+use url::Url;
+
+use crate::actions::{self, Download, Package};
+use crate::checksum::{self, Algorithm};
+use crate::store::Store;
+
+/// What needs to be done to bring a mirror up to date.
+#[derive(Default)]
+pub struct Plan {
+    /// Sources to download into the mirror.
+    pub downloads: Vec<Download>,
+    /// How many sources the mirror already has.
+    pub present: usize,
+    /// Sources in the mirror that didn't match their checksums, which were
+    /// removed to be replaced.
+    pub replaced: Vec<String>,
+    /// How many sources were copied from the source store.
+    pub copied: usize,
+    /// Problems with packages, as '<name>: <problem>'.
+    pub broken: Vec<String>,
+}
+
+/// Get the path of a source in a mirror.
+pub fn mirror_path(dir: &str, url: &str) -> Result<String> {
+    let parsed = Url::parse(url).context(format!("Invalid url {url}"))?;
+    let Some(host) = parsed.host_str() else {
+        bail!("Url {url} has no host");
+    };
+
+    let host = match parsed.port() {
+        Some(x) => format!("{host}:{x}"),
+        None => host.to_string(),
+    };
+
+    // Servers look files up by the decoded path.
+    let path = percent_decode_str(parsed.path()).decode_utf8().context(format!("Invalid url {url}"))?;
+    if path.ends_with('/') || path.split('/').any(|x| x == "..") {
+        bail!("Url {url} doesn't point to a file");
+    }
+
+    Ok(format!("{}/{host}{path}", dir.trim_end_matches('/')))
+}
+
+/// Find every package in some repositories, and work out which of their
+/// remote sources are missing from a mirror. Sources that are in the source
+/// store are copied from there instead of being downloaded.
+pub fn plan(dir: &str, repos: &[String]) -> Result<Plan> {
+    let mut res = Plan::default();
+    let mut seen = HashMap::new();
+
+    for repo in repos {
+        if fs::metadata(repo).is_err() {
+            bail!("Repository {repo} doesn't exist");
+        }
+
+        for pack in glob(&format!("{repo}/*/package.toml"))? {
+            let pack = pack?.parent().unwrap().display().to_string();
+            let name = pack.split('/').next_back().unwrap().to_string();
+            if let Err(e) = plan_package(dir, &pack, &name, &mut seen, &mut res) {
+                res.broken.push(format!("{name}: {e:#}"));
+            }
+        }
+    }
+
+    Ok(res)
+}
+
+/// Work out what to do for the sources of one package. seen holds the package
+/// and checksum that each file in the mirror was first planned for.
+fn plan_package(
+    dir: &str,
+    pack: &str,
+    name: &str,
+    seen: &mut HashMap<String, (String, (Algorithm, String))>,
+    res: &mut Plan,
+) -> Result<()> {
+    let mut toml: Package = actions::parse_package(&vec![pack.to_string()])?.remove(0);
+    toml.name = name.to_string();
+
+    let urls = toml.source_urls()?;
+    let mirrors = toml.source_mirrors()?;
+    for (i, source) in urls.iter().enumerate() {
+        // Git sources are fetched from their repository, and local files come
+        // with the package.
+        if source.starts_with("git+") {
+            continue;
+        }
+
+        let (url, _) = actions::source_file_name(source)?;
+        // Remove any prefixes from the url.
+        let url = if url.get(3..4) == Some("+") { url[4..].to_string() } else { url };
+
+        if !url.starts_with("https://") && !url.starts_with("http://") {
+            continue;
+        }
+
+        // Everything in the mirror has to be verified.
+        let Some(sum) = toml.meta.checksums.get(i).filter(|x| *x != "SKIP") else {
+            bail!("No checksum for source {url}");
+        };
+
+        let (algorithm, hash) = checksum::parse(sum).context(format!("Invalid checksum {sum}"))?;
+
+        // Several packages may use the same source, but there is only room
+        // for one version of it in the mirror.
+        let target = mirror_path(dir, &url)?;
+        if let Some((other, other_sum)) = seen.get(&target) {
+            if *other_sum != (algorithm, hash) {
+                bail!("Checksum for source {url} doesn't match the one in package {other}");
+            }
+
+            continue;
+        }
+
+        seen.insert(target.clone(), (name.to_string(), (algorithm, hash.clone())));
+        if fs::metadata(&target).is_ok() {
+            if checksum::hash_file(&target, algorithm)? == hash {
+                res.present += 1;
+                continue;
+            }
+
+            // The file is corrupt, or was changed upstream after it was
+            // mirrored, so get it again.
+            fs::remove_file(&target).context(format!("Couldn't remove {target}"))?;
+            res.replaced.push(target.clone());
+        }
+
+        let parent = target.rsplit_once('/').unwrap().0;
+        fs::create_dir_all(parent).context(format!("Couldn't create directory {parent}"))?;
+
+        let entry = Store::from_config().path(algorithm, &hash);
+        if fs::metadata(&entry).is_ok() {
+            fs::copy(&entry, &target).context(format!("Couldn't copy {entry} to {target}"))?;
+            res.copied += 1;
+            continue;
+        }
+
+        let mut candidates = actions::mirror_urls(&url);
+        for x in mirrors.get(i).into_iter().flatten() {
+            candidates.append(&mut actions::mirror_urls(x));
+        }
+
+        res.downloads.push(Download {
+            name: name.to_string(),
+            url: url.clone(),
+            urls: candidates,
+            og_url: url,
+            filename: target,
+            checksum: Some(sum.clone()),
+            index: i + 1,
+            total: urls.len(),
+        });
+    }
+
+    Ok(())
+}
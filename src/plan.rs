@@ -0,0 +1,145 @@
+//! This module contains logic to hand the privileged part of a transaction to
+//! a single elevated copy of moss. Everything that needs root (installing and
+//! removing files, hooks, triggers and alternatives) is described by a plan,
+//! which is written to the cache directory and carried out by running
+//! 'moss __apply' through the privilege escalation command, so there is only
+//! one prompt.
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::process::{self, Command};
+
+use anyhow::{bail, Context, Result};
+use nix::unistd::Uid;
+use serde::{Deserialize, Serialize};
+
+use crate::{info_fmt, user_mode, CACHE, CFG};
+use crate::actions;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Plan {
+    /// The cache directory of the process that made the plan, which holds the
+    /// extracted packages. The elevated process may have a different $HOME.
+    pub cache: String,
+    pub install: Vec<PlanPackage>,
+    pub remove: Vec<String>,
+    /// A package and path to swap in from the alternatives store.
+    pub swap: Option<(String, String)>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PlanPackage {
+    pub name: String,
+    pub version: String,
+}
+
+/// Find the command used to become root. If su_cmd is not set, look for one
+/// of 'sudo', 'doas', 'ssu' or 'run0' (in that order).
+pub fn su_command() -> Result<String> {
+    if let Some(x) = &CFG.su_cmd {
+        return Ok(x.clone());
+    }
+
+    for (bin, cmd) in [("sudo", "sudo"), ("doas", "doas"), ("ssu", "ssu --"), ("run0", "run0")] {
+        if fs::metadata(format!("/bin/{bin}")).is_ok() || fs::metadata(format!("/usr/bin/{bin}")).is_ok() {
+            return Ok(cmd.into());
+        }
+    }
+
+    bail!("Couldn't find a command to elevate privileges; set su_cmd in /etc/moss.toml")
+}
+
+/// Build the command that runs 'moss __apply <plan>' as root. If the su_cmd
+/// template contains '{}', it is replaced with the quoted moss command and the
+/// result is split on whitespace (e.g. 'su -c {}'). Otherwise, the moss command
+/// is appended to the template's arguments (e.g. 'sudo' or 'ssu --').
+pub fn elevated_command(template: &str, plan_file: &str) -> Result<Command> {
+    let exe = env::current_exe().context("Couldn't find the path to moss")?;
+    let moss = vec![exe.display().to_string(), "__apply".into(), plan_file.to_string()];
+
+    let mut parts: Vec<String> = vec![];
+    if template.contains("{}") {
+        let quoted: Vec<String> = moss.iter().map(|x| format!("'{}'", x.replace('\'', "'\\''"))).collect();
+        for part in template.split_whitespace() {
+            parts.push(part.replace("{}", &quoted.join(" ")));
+        }
+    } else {
+        parts.extend(template.split_whitespace().map(|x| x.to_string()));
+        parts.extend(moss);
+    }
+
+    if parts.is_empty() {
+        bail!("su_cmd in /etc/moss.toml is empty");
+    }
+
+    let mut cmd = Command::new(&parts[0]);
+    cmd.args(&parts[1..]);
+    Ok(cmd)
+}
+
+/// Carry out a plan as root. If moss is already running as root, or only
+/// installing for the current user, the plan is applied directly, otherwise it
+/// is saved and applied by an elevated moss.
+pub fn execute(plan: &Plan) -> Result<()> {
+    if Uid::effective().is_root() || user_mode() {
+        return apply(plan);
+    }
+
+    let su = su_command()?;
+    info_fmt!("Using {} to become root", su.split_whitespace().next().unwrap_or(""));
+
+    // Other moss processes may be running at the same time, so the plan is
+    // named after this one. It is created fresh, so that an existing file or
+    // symlink at the path is never written through.
+    let plan_file = format!("{}/plan-{}.toml", *CACHE, process::id());
+    let content = toml::to_string(plan).context("Couldn't serialize transaction plan")?;
+    let _ = fs::remove_file(&plan_file);
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&plan_file)
+        .and_then(|mut x| x.write_all(content.as_bytes()))
+        .context(format!("Couldn't write transaction plan to {plan_file}"))?;
+
+    let status = elevated_command(&su, &plan_file)?
+        .status()
+        .context(format!("Couldn't execute {su}"))?;
+
+    let _ = fs::remove_file(&plan_file);
+
+    if !status.success() {
+        bail!("The privileged part of the transaction failed");
+    }
+
+    Ok(())
+}
+
+/// Read a plan written by another moss process and apply it. This is the
+/// entry point of the elevated process.
+pub fn apply_file(plan_file: &String) -> Result<()> {
+    if !Uid::effective().is_root() {
+        bail!("Transaction plans can only be applied as root");
+    }
+
+    let content = fs::read_to_string(plan_file).context(format!("Couldn't read transaction plan {plan_file}"))?;
+    let plan: Plan = toml::from_str(&content).context(format!("Couldn't parse transaction plan {plan_file}"))?;
+    apply(&plan)
+}
+
+/// Apply a plan, assuming we are already root.
+pub fn apply(plan: &Plan) -> Result<()> {
+    if !plan.install.is_empty() {
+        actions::install_plan(plan)?;
+    }
+
+    if !plan.remove.is_empty() {
+        actions::remove_plan(plan)?;
+    }
+
+    actions::swap_plan(plan)?;
+
+    Ok(())
+}
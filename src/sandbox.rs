@@ -0,0 +1,265 @@
+//! This module contains logic to run build scripts inside a sandbox made from
+//! Linux namespaces. The build runs in new user, mount, PID and network
+//! namespaces, chrooted into a directory where only the read-only system
+//! paths, the package directory, and the src and dest dirs are visible, with
+//! no network access other than an unconfigured loopback interface. /dev only
+//! has a few harmless device nodes from the host.
+
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use nix::errno::Errno;
+use nix::libc;
+use nix::mount::{mount, MsFlags};
+use nix::sched::{unshare, CloneFlags};
+use nix::sys::prctl;
+use nix::sys::signal::Signal;
+use nix::sys::stat::Mode;
+use nix::sys::statvfs::{statvfs, FsFlags};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{chdir, chroot, fork, getgid, getuid, mkdir, symlinkat, ForkResult};
+
+/// Paths from the host that are visible read-only inside the sandbox, if they
+/// exist.
+const SYSTEM_PATHS: [&str; 8] = ["/usr", "/bin", "/sbin", "/lib", "/lib32", "/lib64", "/etc", "/opt"];
+
+/// Device nodes from the host that are visible inside the sandbox, if they
+/// exist. The rest of /dev is left out.
+const DEV_NODES: [&str; 6] = ["/dev/null", "/dev/zero", "/dev/full", "/dev/random", "/dev/urandom", "/dev/tty"];
+
+/// Symlinks that are normally in /dev.
+const DEV_LINKS: [(&str, &str); 4] = [
+    ("/proc/self/fd", "/dev/fd"),
+    ("/proc/self/fd/0", "/dev/stdin"),
+    ("/proc/self/fd/1", "/dev/stdout"),
+    ("/proc/self/fd/2", "/dev/stderr"),
+];
+
+/// A step in setting up the sandbox root. Everything is prepared before the
+/// fork, since the child may only make system calls until it execs.
+#[derive(Clone, Debug)]
+enum Step {
+    /// Create a directory if it doesn't exist yet.
+    Dir(CString),
+    /// Create an empty file if it doesn't exist yet, to bind a file to.
+    File(CString),
+    /// Bind a host path to the target in the sandbox root, then remount it
+    /// with the given flags if it should be read-only.
+    Bind { source: CString, target: CString, remount: Option<MsFlags> },
+    /// Mount a new filesystem of the given type at the target. If it isn't
+    /// required, failing to mount it is ignored.
+    New { target: CString, kind: CString, required: bool },
+    /// Create a symlink at the target pointing to the source.
+    Link { source: CString, target: CString },
+}
+
+/// Set up a command to run inside a sandbox. The sandbox root is created in
+/// build_dir, and the src and dest dirs are writable inside it, while the
+/// package directory is read-only.
+pub fn apply(cmd: &mut Command, build_dir: &str, pkg_dir: &str, src_dir: &str, dest_dir: &str) -> Result<()> {
+    let root = format!("{build_dir}/root");
+    let pkg_dir = fs::canonicalize(pkg_dir)
+        .context(format!("Couldn't canonicalize path {pkg_dir}"))?
+        .display()
+        .to_string();
+
+    let mut steps = vec![];
+    let bind = |steps: &mut Vec<Step>, path: &str, read_only: bool| -> Result<()> {
+        let target = format!("{root}{path}");
+        let is_dir = fs::metadata(path).map(|x| x.is_dir()).unwrap_or(true);
+        if is_dir {
+            steps.extend(dirs(&root, &target)?);
+        } else {
+            steps.extend(dirs(&root, target.rsplit_once('/').unwrap().0)?);
+            steps.push(Step::File(c_path(&target)?));
+        }
+
+        steps.push(Step::Bind {
+            source: c_path(path)?,
+            target: c_path(&target)?,
+            remount: if read_only { Some(remount_flags(path)?) } else { None },
+        });
+
+        Ok(())
+    };
+
+    for path in SYSTEM_PATHS {
+        if fs::metadata(path).is_ok() {
+            bind(&mut steps, path, true)?;
+        }
+    }
+
+    // Only the harmless device nodes are visible, in a /dev of our own.
+    let new = |steps: &mut Vec<Step>, path: &str, kind: &str, required: bool| -> Result<()> {
+        let target = format!("{root}{path}");
+        steps.extend(dirs(&root, &target)?);
+        steps.push(Step::New { target: c_path(&target)?, kind: c_path(kind)?, required });
+        Ok(())
+    };
+
+    new(&mut steps, "/dev", "tmpfs", true)?;
+    for node in DEV_NODES {
+        if fs::metadata(node).is_ok() {
+            bind(&mut steps, node, false)?;
+        }
+    }
+
+    for (source, target) in DEV_LINKS {
+        steps.push(Step::Link { source: c_path(source)?, target: c_path(&format!("{root}{target}"))? });
+    }
+
+    // Some container runtimes don't allow mounting a new /proc, so the build
+    // has to make do without one.
+    new(&mut steps, "/dev/shm", "tmpfs", false)?;
+    new(&mut steps, "/proc", "proc", false)?;
+    new(&mut steps, "/tmp", "tmpfs", true)?;
+    bind(&mut steps, &pkg_dir, true)?;
+    bind(&mut steps, src_dir, false)?;
+    bind(&mut steps, dest_dir, false)?;
+
+    fs::create_dir_all(&root).context(format!("Couldn't create directory {root}"))?;
+
+    let root = c_path(&root)?;
+    let work_dir = c_path(src_dir)?;
+    let uid_map = format!("{} {} 1", getuid(), getuid()).into_bytes();
+    let gid_map = format!("{} {} 1", getgid(), getgid()).into_bytes();
+
+    unsafe {
+        cmd.pre_exec(move || enter(&root, &steps, &work_dir, &uid_map, &gid_map));
+    }
+
+    Ok(())
+}
+
+fn c_path(path: &str) -> Result<CString> {
+    CString::new(path).context(format!("Path {path} contains a null byte"))
+}
+
+/// Get the steps to create a directory inside the sandbox root and all of its
+/// parents below the root. Mount points are created as we go, since paths like
+/// the cache directory may be under a mount made earlier (e.g. /tmp).
+fn dirs(root: &str, path: &str) -> Result<Vec<Step>> {
+    let mut res = vec![];
+    for (i, _) in path.match_indices('/').skip(1) {
+        if i > root.len() {
+            res.push(Step::Dir(c_path(&path[..i])?));
+        }
+    }
+
+    res.push(Step::Dir(c_path(path)?));
+    Ok(res)
+}
+
+/// Work out the flags needed to remount a bind mount of a path read-only.
+/// Inside a user namespace, flags such as nosuid that are already set on the
+/// original mount are locked, so they have to be carried over.
+fn remount_flags(path: &str) -> Result<MsFlags> {
+    let stat = statvfs(path).context(format!("Couldn't stat filesystem of {path}"))?;
+    let mut flags = MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY;
+
+    for (st, ms) in [
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ] {
+        if stat.flags().contains(st) {
+            flags |= ms;
+        }
+    }
+
+    Ok(flags)
+}
+
+/// Write to a file in /proc without allocating.
+fn write_proc(path: &CStr, data: &[u8]) -> io::Result<()> {
+    let fd = unsafe { libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let n = unsafe { libc::write(fd, data.as_ptr().cast(), data.len()) };
+    let res = if n < 0 { Err(io::Error::last_os_error()) } else { Ok(()) };
+    unsafe { libc::close(fd) };
+    res
+}
+
+/// Ignore an error if something already exists.
+fn exists_ok(res: nix::Result<()>) -> io::Result<()> {
+    match res {
+        Err(Errno::EEXIST) => Ok(()),
+        x => Ok(x?),
+    }
+}
+
+/// Enter the sandbox. This runs in the child process between fork and exec,
+/// where allocating isn't safe, so it only makes system calls with what apply
+/// prepared.
+fn enter(root: &CStr, steps: &[Step], work_dir: &CStr, uid_map: &[u8], gid_map: &[u8]) -> io::Result<()> {
+    unshare(
+        CloneFlags::CLONE_NEWUSER
+        | CloneFlags::CLONE_NEWNS
+        | CloneFlags::CLONE_NEWPID
+        | CloneFlags::CLONE_NEWNET
+    )?;
+
+    write_proc(c"/proc/self/setgroups", b"deny")?;
+    write_proc(c"/proc/self/uid_map", uid_map)?;
+    write_proc(c"/proc/self/gid_map", gid_map)?;
+
+    // Only children of this process are in the new PID namespace, so fork
+    // once more. This process just waits and passes on the exit status.
+    if let ForkResult::Parent { child } = unsafe { fork() }? {
+        let code = match waitpid(child, None) {
+            Ok(WaitStatus::Exited(_, code)) => code,
+            Ok(WaitStatus::Signaled(_, sig, _)) => 128 + sig as i32,
+            _ => 1,
+        };
+
+        unsafe { libc::_exit(code) };
+    }
+
+    // The build is PID 1 in its namespace, which ignores signals like SIGINT,
+    // so make sure it dies along with its parent.
+    prctl::set_pdeathsig(Signal::SIGKILL)?;
+
+    // Keep our mounts from propagating back to the host.
+    mount(None::<&str>, c"/", None::<&str>, MsFlags::MS_REC | MsFlags::MS_PRIVATE, None::<&str>)?;
+
+    for step in steps {
+        match step {
+            Step::Dir(path) => exists_ok(mkdir(path.as_c_str(), Mode::from_bits_truncate(0o755)))?,
+            Step::File(path) => {
+                let fd = unsafe { libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CREAT | libc::O_CLOEXEC, 0o644) };
+                if fd < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+
+                unsafe { libc::close(fd) };
+            },
+            Step::Bind { source, target, remount } => {
+                mount(Some(source.as_c_str()), target.as_c_str(), None::<&str>, MsFlags::MS_BIND | MsFlags::MS_REC, None::<&str>)?;
+                if let Some(flags) = remount {
+                    mount(None::<&str>, target.as_c_str(), None::<&str>, *flags, None::<&str>)?;
+                }
+            },
+            Step::New { target, kind, required } => {
+                let res = mount(Some(kind.as_c_str()), target.as_c_str(), Some(kind.as_c_str()), MsFlags::empty(), None::<&str>);
+                if *required {
+                    res?;
+                }
+            },
+            Step::Link { source, target } => exists_ok(symlinkat(source.as_c_str(), None, target.as_c_str()))?,
+        }
+    }
+
+    chroot(root)?;
+    chdir(work_dir)?;
+    Ok(())
+}
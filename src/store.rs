@@ -0,0 +1,104 @@
+//! This module contains logic for the source store, where downloaded sources
+//! are kept under their checksums as <store>/<algorithm>/<hash>. The files in
+//! each package's download cache are symlinks into the store, so a file that is
+//! renamed or re-tagged upstream gets a new entry instead of being mistaken for
+//! the old one, and a file used by several packages is only kept once. The
+//! store can be shared between machines with the 'store_dir' config option.
+
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{Context, Result};
+
+use crate::checksum::Algorithm;
+use crate::STORE;
+
+/// Counts the files added to the store by this process, to give each one its
+/// own part file.
+static INSERTED: AtomicUsize = AtomicUsize::new(0);
+
+pub struct Store {
+    pub dir: String,
+}
+
+impl Store {
+    /// Get the store set with the 'store_dir' config option, or the one in the
+    /// cache.
+    pub fn from_config() -> Store {
+        Store { dir: STORE.clone() }
+    }
+
+    /// Get the path of the entry for a checksum.
+    pub fn path(&self, algorithm: Algorithm, hash: &str) -> String {
+        format!("{}/{algorithm}/{hash}", self.dir)
+    }
+
+    /// Whether a file in the download cache is a symlink to the entry for a
+    /// checksum. Entries are only added once they match their checksum, so
+    /// such a file doesn't need to be hashed again.
+    pub fn is_linked(&self, file: &str, algorithm: Algorithm, hash: &str) -> bool {
+        let entry = self.path(algorithm, hash);
+        fs::read_link(file).map(|x| x == Path::new(&entry)).unwrap_or(false) && fs::metadata(&entry).is_ok()
+    }
+
+    /// Point a file in the download cache at the entry for a checksum, if the
+    /// store has it. Returns whether it does.
+    pub fn link(&self, file: &str, algorithm: Algorithm, hash: &str) -> Result<bool> {
+        let entry = self.path(algorithm, hash);
+        if fs::metadata(&entry).is_err() {
+            return Ok(false);
+        }
+
+        if !self.is_linked(file, algorithm, hash) {
+            let _ = fs::remove_file(file);
+            symlink(&entry, file).context(format!("Couldn't link {file} to {entry}"))?;
+        }
+
+        Ok(true)
+    }
+
+    /// Move a file in the download cache, which is known to match a checksum,
+    /// into the store and leave a symlink in its place. If the store already
+    /// has the file, the copy in the download cache is dropped.
+    pub fn insert(&self, file: &str, algorithm: Algorithm, hash: &str) -> Result<()> {
+        if self.is_linked(file, algorithm, hash) {
+            return Ok(());
+        }
+
+        let entry = self.path(algorithm, hash);
+        if fs::metadata(&entry).is_err() {
+            let dir = format!("{}/{algorithm}", self.dir);
+            fs::create_dir_all(&dir).context(format!("Couldn't create directory {dir}"))?;
+
+            // The file may itself be a symlink into the store (under another
+            // algorithm), in which case the contents are copied. The entry
+            // only appears once it is complete. Other processes, possibly on
+            // other machines, may be adding the same file, so the part file
+            // is named after this process.
+            let is_link = fs::symlink_metadata(file).map(|x| x.is_symlink()).unwrap_or(false);
+            let part = format!("{entry}.{}-{}-{}.part", hostname(), process::id(), INSERTED.fetch_add(1, Ordering::Relaxed));
+            let res = if is_link {
+                fs::copy(file, &part).map(|_| ()).context(format!("Couldn't copy {file} to {part}"))
+            } else {
+                fs::rename(file, &part).or_else(|_| fs::copy(file, &part).map(|_| ()))
+                    .context(format!("Couldn't move {file} to {part}"))
+            };
+
+            if let Err(e) = res.and_then(|_| fs::rename(&part, &entry).context(format!("Couldn't move {part} to {entry}"))) {
+                let _ = fs::remove_file(&part);
+                return Err(e);
+            }
+        }
+
+        self.link(file, algorithm, hash)?;
+        Ok(())
+    }
+}
+
+/// Get the name of this machine, for naming files in a shared store.
+fn hostname() -> String {
+    nix::unistd::gethostname().ok().and_then(|x| x.into_string().ok()).unwrap_or_default()
+}
@@ -0,0 +1,80 @@
+//! This module contains logic to run system-wide triggers, which are commands
+//! that run once at the end of a transaction if any of the installed or
+//! removed files match one of their globs (e.g. ldconfig for libraries).
+
+use std::fs;
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use glob::{glob, Pattern};
+use serde::Deserialize;
+
+use crate::{info_fmt, user_mode, CFG};
+use crate::log;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Trigger {
+    pub paths: Vec<String>,
+    pub command: String,
+    #[serde(skip)]
+    pub name: String,
+}
+
+/// Read all trigger definitions from the trigger directory. Each trigger is a
+/// file named <name>.toml.
+pub fn load() -> Result<Vec<Trigger>> {
+    let dir = CFG.trigger_dir.clone().unwrap_or("/etc/moss/triggers".into());
+    let mut res = vec![];
+
+    for file in glob(&format!("{dir}/*.toml"))? {
+        let file = file?;
+        let content = fs::read_to_string(&file)
+            .context(format!("Failed to read trigger {}", file.display()))?;
+
+        let mut trigger: Trigger = toml::from_str(&content).context(format!("{}", file.display()))?;
+        trigger.name = file.file_stem().unwrap().to_str().unwrap().to_string();
+        res.push(trigger);
+    }
+
+    Ok(res)
+}
+
+/// Run every trigger that matches at least one of the given paths, once each.
+/// Output from the trigger is shown indented under its name. Triggers are
+/// system-wide, so they don't run for user mode transactions.
+pub fn run(paths: &[String]) -> Result<()> {
+    if user_mode() {
+        return Ok(());
+    }
+
+    for trigger in load()? {
+        let mut patterns = vec![];
+        for p in &trigger.paths {
+            patterns.push(Pattern::new(p).context(format!("Invalid glob {p} in trigger {}", trigger.name))?);
+        }
+
+        if !paths.iter().any(|x| patterns.iter().any(|p| p.matches(x))) {
+            continue;
+        }
+
+        info_fmt!("Running trigger \x1b[36m{}\x1b[0m", trigger.name);
+        let output = Command::new("sh")
+            .args(["-c", &trigger.command])
+            .output()
+            .context(format!("Couldn't execute trigger {}", trigger.name))?;
+
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            log::info_ident(line);
+        }
+
+        for line in String::from_utf8_lossy(&output.stderr).lines() {
+            log::info_ident(line);
+        }
+
+        if !output.status.success() {
+            log::warn(&format!("Trigger {} failed", trigger.name));
+        }
+    }
+
+    Ok(())
+}
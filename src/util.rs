@@ -1,8 +1,14 @@
 //! This module contains some miscellaneous utility functions.
 
+use std::env;
+use std::fs::{self, File};
 use std::io::{Read, Write};
 
+use anyhow::{bail, Context, Result};
 use indicatif::{ProgressBar, ProgressStyle};
+use nix::sys::prctl;
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{fork, pipe, setgid, setgroups, setuid, ForkResult, User};
 
 /// Write data from a stream to two different outputs concurrently.
 pub fn tee(
@@ -33,3 +39,64 @@ pub fn inc_bar(bar: &ProgressBar, amt: u64, len: usize, style: &ProgressStyle) {
         bar.inc(amt)
     }
 }
+
+/// Run a function in a child process as another user, and wait for it to
+/// finish. If the function fails, the error is sent back through a pipe and
+/// returned in the parent.
+///
+/// The child isn't a fresh process, but a fork that keeps running Rust code,
+/// which is only sound if no other thread could be holding a lock (e.g. in the
+/// allocator) at the time. So this fails unless the caller is the only thread
+/// running.
+pub fn run_as(user: &User, f: impl FnOnce() -> Result<()>) -> Result<()> {
+    let threads = fs::read_dir("/proc/self/task").context("Couldn't list threads")?.count();
+    if threads > 1 {
+        bail!("Couldn't run as {}: {} threads are running", user.name, threads);
+    }
+
+    let (read, write) = pipe().context("Couldn't create pipe")?;
+
+    match unsafe { fork() }.context("Couldn't fork")? {
+        ForkResult::Child => {
+            drop(read);
+            let mut write = File::from(write);
+            let code = match drop_privileges(user).and_then(|_| f()) {
+                Ok(_) => 0,
+                Err(e) => {
+                    let _ = write!(write, "{:#}", e);
+                    1
+                },
+            };
+
+            std::process::exit(code);
+        },
+        ForkResult::Parent { child } => {
+            drop(write);
+            let mut msg = String::new();
+            File::from(read).read_to_string(&mut msg).context("Couldn't read from pipe")?;
+
+            let status = waitpid(child, None).context("Couldn't wait on child process")?;
+            if !msg.is_empty() {
+                bail!("{msg}");
+            }
+
+            match status {
+                WaitStatus::Exited(_, 0) => Ok(()),
+                _ => bail!("Child process running as {} failed", user.name),
+            }
+        },
+    }
+}
+
+/// Permanently switch to another user, including their groups and $HOME.
+fn drop_privileges(user: &User) -> Result<()> {
+    setgroups(&[user.gid]).context(format!("Couldn't set groups for {}", user.name))?;
+    setgid(user.gid).context(format!("Couldn't set gid to {}", user.gid))?;
+    setuid(user.uid).context(format!("Couldn't set uid to {}", user.uid))?;
+
+    // Changing uid makes the process non-dumpable, which leaves its /proc
+    // files owned by root, so the sandbox couldn't write its uid map.
+    prctl::set_dumpable(true).context("Couldn't make process dumpable")?;
+    env::set_var("HOME", &user.dir);
+    Ok(())
+}
@@ -0,0 +1,28 @@
+//! Helpers shared by the tests.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// An empty temporary directory for a test, which is removed again once the
+/// test is done with it.
+pub struct TestDir {
+    pub path: PathBuf,
+}
+
+impl TestDir {
+    /// Make the directory. Names only need to be unique within a test file,
+    /// since the process id is part of the path.
+    pub fn new(name: &str) -> TestDir {
+        let path = env::temp_dir().join(format!("moss-test-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TestDir { path }
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
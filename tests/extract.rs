@@ -0,0 +1,93 @@
+//! Tests for extracting tarballs, against archives built on the fly.
+
+mod common;
+
+use std::fs::{self, File};
+use std::path::Path;
+
+use common::TestDir;
+use moss::extract::{self, Compression, Kind};
+use tar::{Builder, EntryType, Header};
+
+fn header(kind: EntryType, size: usize) -> Header {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(kind);
+    header.set_size(size as u64);
+    header.set_mode(if kind == EntryType::Directory { 0o755 } else { 0o644 });
+    header
+}
+
+fn add_dir(builder: &mut Builder<File>, path: &str) {
+    builder.append_data(&mut header(EntryType::Directory, 0), path, &[][..]).unwrap();
+}
+
+fn add_file(builder: &mut Builder<File>, path: &str, data: &str) {
+    builder.append_data(&mut header(EntryType::Regular, data.len()), path, data.as_bytes()).unwrap();
+}
+
+fn extract(archive: &Path, dest: &Path) -> anyhow::Result<()> {
+    let archive = archive.display().to_string();
+    let dest = dest.display().to_string();
+    extract::extract(&archive, Kind::Tar(Compression::None), &dest, None)
+}
+
+#[test]
+fn strips_git_archive() {
+    let dir = TestDir::new("git-archive");
+    let archive = dir.path.join("proj.tar");
+    let mut builder = Builder::new(File::create(&archive).unwrap());
+
+    // Like 'git archive --prefix=proj-1.0/', which starts with a pax global
+    // header holding the commit.
+    let comment = format!("52 comment={}\n", "a".repeat(40));
+    let mut global = header(EntryType::XGlobalHeader, comment.len());
+    builder.append_data(&mut global, "pax_global_header", comment.as_bytes()).unwrap();
+
+    // A path this long needs a GNU long name entry before it.
+    let long = format!("proj-1.0/{}/file", "d".repeat(120));
+    add_dir(&mut builder, "proj-1.0/");
+    add_file(&mut builder, "proj-1.0/README", "readme\n");
+    add_file(&mut builder, &long, "long\n");
+    builder.finish().unwrap();
+
+    let dest = dir.path.join("src");
+    extract(&archive, &dest).unwrap();
+    assert_eq!(fs::read_to_string(dest.join("README")).unwrap(), "readme\n");
+    assert_eq!(fs::read_to_string(dest.join(long.strip_prefix("proj-1.0/").unwrap())).unwrap(), "long\n");
+    assert!(fs::metadata(dest.join("pax_global_header")).is_err());
+    assert!(fs::metadata(dest.join("proj-1.0")).is_err());
+}
+
+#[test]
+fn keeps_archive_without_top_level_dir() {
+    let dir = TestDir::new("no-top-level");
+    let archive = dir.path.join("flat.tar");
+    let mut builder = Builder::new(File::create(&archive).unwrap());
+    add_file(&mut builder, "a/file", "a\n");
+    add_file(&mut builder, "b", "b\n");
+    builder.finish().unwrap();
+
+    let dest = dir.path.join("src");
+    extract(&archive, &dest).unwrap();
+    assert_eq!(fs::read_to_string(dest.join("a/file")).unwrap(), "a\n");
+    assert_eq!(fs::read_to_string(dest.join("b")).unwrap(), "b\n");
+}
+
+#[test]
+fn refuses_writing_through_symlink() {
+    let dir = TestDir::new("symlink");
+    let outside = dir.path.join("outside");
+    fs::create_dir_all(&outside).unwrap();
+
+    let archive = dir.path.join("evil.tar");
+    let mut builder = Builder::new(File::create(&archive).unwrap());
+    add_dir(&mut builder, "proj/");
+    let mut link = header(EntryType::Symlink, 0);
+    builder.append_link(&mut link, "proj/link", &outside).unwrap();
+    add_file(&mut builder, "proj/link/escaped", "escaped\n");
+    builder.finish().unwrap();
+
+    let err = extract(&archive, &dir.path.join("src")).unwrap_err();
+    assert!(format!("{err:#}").contains("is a symlink"));
+    assert!(fs::metadata(outside.join("escaped")).is_err());
+}
@@ -0,0 +1,125 @@
+//! Tests for finding unused files, against a cache and a repository made up in
+//! a temporary directory.
+
+mod common;
+
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::Path;
+use std::time::Duration;
+
+use common::TestDir;
+use moss::actions;
+use moss::checksum;
+use moss::gc::{self, Layout};
+use moss::store::Store;
+
+/// Make a repository with one package, foo, and a cache with files that foo
+/// does and doesn't use, in a test dir.
+fn setup(name: &str) -> (TestDir, Layout) {
+    let dir = TestDir::new(name);
+    let root = dir.path.display().to_string();
+    let cache = format!("{root}/cache");
+
+    let sum = blake3::hash(b"foo").to_string();
+    let (algorithm, hash) = checksum::parse(&sum).unwrap();
+    write(&format!("{root}/repo/foo/package.toml"), &format!("[meta]
+version = \"1.0\"
+maintainer = \"\"
+sources = [\"https://example.com/foo-{{version}}.tar.gz\", \"git+https://example.com/bar.git#v1\"]
+checksums = [\"{sum}\", \"SKIP\"]
+
+[deps]
+
+[mkdeps]
+"));
+
+    let entry = format!("{cache}/store/{algorithm}/{hash}");
+    write(&entry, "foo");
+    write(&format!("{cache}/store/{algorithm}/{}", blake3::hash(b"old")), "old");
+    write(&format!("{entry}.host-1-0.part"), "fo");
+
+    fs::create_dir_all(format!("{cache}/dl/foo")).unwrap();
+    symlink(&entry, format!("{cache}/dl/foo/foo-1.0.tar.gz")).unwrap();
+    write(&format!("{cache}/dl/foo/foo-0.9.tar.gz"), "old");
+    write(&format!("{cache}/dl/foo/foo-0.8.tar.gz.part"), "ol");
+    write(&format!("{cache}/dl/gone/gone.tar.gz"), "gone");
+
+    let mirror = actions::git_mirror_name("https://example.com/bar.git");
+    fs::create_dir_all(format!("{cache}/git/{mirror}")).unwrap();
+    fs::create_dir_all(format!("{cache}/git/gone-0123456789ab")).unwrap();
+
+    write(&format!("{cache}/bin/foo@1.0.tar.gz"), "");
+    write(&format!("{cache}/bin/foo@0.9.tar.gz"), "");
+    write(&format!("{cache}/bin/baz@2.0.tar.gz"), "");
+    write(&format!("{root}/db/installed/baz@2.0"), "");
+
+    write(&format!("{cache}/build/foo/src/file"), "");
+    write(&format!("{cache}/tmp/foo/file"), "");
+
+    let layout = Layout {
+        store: Store { dir: format!("{cache}/store") },
+        shared_store: false,
+        bins: format!("{cache}/bin"),
+        cache,
+        repos: vec![format!("{root}/repo")],
+        db: format!("{root}/db"),
+    };
+
+    (dir, layout)
+}
+
+fn write(path: &str, data: &str) {
+    fs::create_dir_all(Path::new(path).parent().unwrap()).unwrap();
+    fs::write(path, data).unwrap();
+}
+
+/// Find the unused files, as paths relative to the cache and the reasons they
+/// can be removed.
+fn unused(layout: &Layout, min_age: Option<Duration>, busy: bool) -> Vec<(String, &'static str)> {
+    gc::unused(layout, min_age, busy).unwrap().into_iter().map(|x| {
+        let path = x.path.strip_prefix(&format!("{}/", layout.cache)).unwrap().to_string();
+        (path.replace(&blake3::hash(b"old").to_string(), "<old>"), x.reason)
+    }).collect()
+}
+
+#[test]
+fn finds_unused_files() {
+    let (_dir, layout) = setup("unused");
+    let mut found = unused(&layout, None, false);
+    found.sort();
+
+    assert_eq!(found, vec![
+        ("bin/foo@0.9.tar.gz".to_string(), "old binary"),
+        ("build/foo".to_string(), "leftover build dir"),
+        ("dl/foo/foo-0.8.tar.gz.part".to_string(), "partial download"),
+        ("dl/foo/foo-0.9.tar.gz".to_string(), "unused source"),
+        ("dl/gone".to_string(), "unused sources"),
+        ("git/gone-0123456789ab".to_string(), "unused git mirror"),
+        ("store/b3/<old>".to_string(), "unused source"),
+        ("tmp/foo".to_string(), "leftover tmp dir"),
+    ]);
+}
+
+#[test]
+fn leaves_files_in_use_alone() {
+    let (_dir, layout) = setup("busy");
+    let found = unused(&layout, None, true);
+    assert!(found.iter().all(|(path, _)| !path.starts_with("build/") && !path.starts_with("tmp/")));
+    assert!(found.iter().all(|(path, _)| !path.ends_with(".part")));
+    assert!(found.iter().any(|(path, _)| path == "dl/foo/foo-0.9.tar.gz"));
+}
+
+#[test]
+fn keeps_recent_files() {
+    let (_dir, layout) = setup("recent");
+    assert!(unused(&layout, Some(Duration::from_secs(24 * 60 * 60)), false).is_empty());
+    assert_eq!(unused(&layout, Some(Duration::ZERO), false).len(), 8);
+}
+
+#[test]
+fn leaves_shared_store_alone() {
+    let (_dir, mut layout) = setup("shared");
+    layout.shared_store = true;
+    assert!(unused(&layout, None, false).iter().all(|(path, _)| !path.starts_with("store/")));
+}
@@ -0,0 +1,288 @@
+//! Tests for downloading files, against a small HTTP server running on a local
+//! port.
+
+mod common;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use common::TestDir;
+use indicatif::{ProgressBar, ProgressStyle};
+use moss::http::{self, Client};
+
+/// A server that handles a few paths:
+///
+/// /file      the test data, honouring Range requests
+/// /redirect  a relative redirect to /file
+/// /loop      a redirect to itself
+/// /flaky     a 503 for the first two requests, then the test data
+/// /cut       half of the test data the first time, then the test data
+/// /slow      the test data after two seconds
+///
+/// Requests with an absolute url, which are sent to proxies, are answered as if
+/// they were for the path of the url, and recorded in proxied.
+struct Server {
+    addr: String,
+    proxied: Arc<Mutex<Vec<String>>>,
+}
+
+fn data() -> Vec<u8> {
+    (0..200_000u32).map(|x| (x % 251) as u8).collect()
+}
+
+impl Server {
+    fn start() -> Server {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let proxied = Arc::new(Mutex::new(vec![]));
+        let counts = Arc::new(Mutex::new(HashMap::new()));
+
+        let server_proxied = proxied.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let proxied = server_proxied.clone();
+                let counts = counts.clone();
+                thread::spawn(move || handle(stream.unwrap(), proxied, counts));
+            }
+        });
+
+        Server { addr, proxied }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("http://{}{path}", self.addr)
+    }
+}
+
+fn handle(mut stream: TcpStream, proxied: Arc<Mutex<Vec<String>>>, counts: Arc<Mutex<HashMap<String, usize>>>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    let target = line.split(' ').nth(1).unwrap_or("/").to_string();
+
+    let mut range = None;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).unwrap();
+        if header.trim().is_empty() {
+            break;
+        }
+
+        let (key, val) = header.split_once(':').unwrap();
+        if key.eq_ignore_ascii_case("range") {
+            let start = val.trim().trim_start_matches("bytes=").trim_end_matches('-');
+            range = Some(start.parse::<usize>().unwrap());
+        }
+    }
+
+    let path = match target.strip_prefix("http://") {
+        Some(x) => {
+            proxied.lock().unwrap().push(target.clone());
+            format!("/{}", x.split_once('/').unwrap().1)
+        },
+        None => target,
+    };
+
+    let count = {
+        let mut counts = counts.lock().unwrap();
+        let count = counts.entry(path.clone()).or_insert(0);
+        *count += 1;
+        *count
+    };
+
+    let data = data();
+    let respond = |stream: &mut TcpStream, status: &str, headers: &[String], body: &[u8]| {
+        let mut res = format!("HTTP/1.1 {status}\r\nConnection: close\r\n");
+        for header in headers {
+            res += &format!("{header}\r\n");
+        }
+
+        res += "\r\n";
+        let _ = stream.write_all(res.as_bytes());
+        let _ = stream.write_all(body);
+    };
+
+    let send_file = |stream: &mut TcpStream| match range {
+        Some(start) => respond(
+            stream,
+            "206 Partial Content",
+            &[
+                format!("Content-Length: {}", data.len() - start),
+                format!("Content-Range: bytes {start}-{}/{}", data.len() - 1, data.len()),
+            ],
+            &data[start..],
+        ),
+        None => respond(stream, "200 OK", &[format!("Content-Length: {}", data.len())], &data),
+    };
+
+    match path.as_str() {
+        "/file" => send_file(&mut stream),
+        "/redirect" => respond(&mut stream, "302 Found", &["Location: file".into(), "Content-Length: 0".into()], b""),
+        "/loop" => respond(&mut stream, "302 Found", &["Location: /loop".into(), "Content-Length: 0".into()], b""),
+        "/flaky" if count <= 2 => respond(&mut stream, "503 Service Unavailable", &["Content-Length: 0".into()], b""),
+        "/flaky" => send_file(&mut stream),
+        "/cut" if count == 1 => {
+            respond(&mut stream, "200 OK", &[format!("Content-Length: {}", data.len())], &data[..data.len() / 2]);
+        },
+        "/cut" => send_file(&mut stream),
+        "/slow" => {
+            thread::sleep(Duration::from_secs(2));
+            send_file(&mut stream);
+        },
+        _ => respond(&mut stream, "404 Not Found", &["Content-Length: 0".into()], b""),
+    }
+}
+
+fn client() -> Client {
+    // Make sure that requests to the test server don't go through a proxy
+    // from the environment.
+    env::set_var("no_proxy", "127.0.0.1");
+
+    Client {
+        timeout: Duration::from_millis(500),
+        retries: 2,
+        backoff: Duration::from_millis(10),
+        max_redirects: 5,
+        proxy: None,
+    }
+}
+
+/// Download a url to a new file in a test dir, returning the path of the file
+/// and the result.
+fn download(dir: &TestDir, client: &Client, url: &String, checksum: Option<&String>, name: &str) -> (String, anyhow::Result<()>) {
+    let filename = dir.path.join(name).display().to_string();
+
+    let res = client.download(url, &filename, checksum, &ProgressBar::hidden(), &ProgressStyle::default_bar());
+    (filename, res)
+}
+
+#[test]
+fn downloads_file() {
+    let dir = TestDir::new("file");
+    let server = Server::start();
+    let (file, res) = download(&dir, &client(), &server.url("/file"), None, "file");
+    res.unwrap();
+    assert_eq!(fs::read(&file).unwrap(), data());
+    assert!(fs::metadata(format!("{file}.part")).is_err());
+}
+
+#[test]
+fn checks_checksum() {
+    let dir = TestDir::new("checksum");
+    let server = Server::start();
+    let sum = blake3::hash(&data()).to_string();
+    let (_, res) = download(&dir, &client(), &server.url("/file"), Some(&sum), "checksum-ok");
+    res.unwrap();
+
+    let bad = format!("sha256:{}", "0".repeat(64));
+    let (file, res) = download(&dir, &client(), &server.url("/file"), Some(&bad), "checksum-bad");
+    assert!(format!("{:#}", res.unwrap_err()).contains("Checksum mismatch"));
+    assert!(fs::metadata(&file).is_err());
+}
+
+#[test]
+fn resumes_part_file() {
+    let server = Server::start();
+    let dir = TestDir::new("resume");
+    let file = dir.path.join("resume").display().to_string();
+    // Only the rest of the file should be downloaded, so what is already in
+    // the part file is kept.
+    fs::write(format!("{file}.part"), [0xff; 1000]).unwrap();
+
+    client().download(&server.url("/file"), &file, None, &ProgressBar::hidden(), &ProgressStyle::default_bar()).unwrap();
+    let mut expected = vec![0xff; 1000];
+    expected.extend(&data()[1000..]);
+    assert_eq!(fs::read(&file).unwrap(), expected);
+}
+
+#[test]
+fn retries_interrupted_download() {
+    let dir = TestDir::new("cut");
+    let server = Server::start();
+    let (file, res) = download(&dir, &client(), &server.url("/cut"), None, "cut");
+    res.unwrap();
+    assert_eq!(fs::read(&file).unwrap(), data());
+}
+
+#[test]
+fn retries_server_errors() {
+    let dir = TestDir::new("flaky");
+    let server = Server::start();
+    let (file, res) = download(&dir, &client(), &server.url("/flaky"), None, "flaky");
+    res.unwrap();
+    assert_eq!(fs::read(&file).unwrap(), data());
+
+    let mut client = client();
+    client.retries = 1;
+    let server = Server::start();
+    let (_, res) = download(&dir, &client, &server.url("/flaky"), None, "flaky-no-retries");
+    assert!(format!("{:#}", res.unwrap_err()).contains("503"));
+}
+
+#[test]
+fn does_not_retry_missing_files() {
+    let dir = TestDir::new("missing");
+    let server = Server::start();
+    let (file, res) = download(&dir, &client(), &server.url("/missing"), None, "missing");
+    let err = format!("{:#}", res.unwrap_err());
+    assert!(err.contains("404"));
+    assert!(!err.contains("Giving up"));
+    assert!(fs::metadata(format!("{file}.part")).is_err());
+}
+
+#[test]
+fn follows_relative_redirects() {
+    let dir = TestDir::new("redirect");
+    let server = Server::start();
+    let (file, res) = download(&dir, &client(), &server.url("/redirect"), None, "redirect");
+    res.unwrap();
+    assert_eq!(fs::read(&file).unwrap(), data());
+}
+
+#[test]
+fn limits_redirects() {
+    let dir = TestDir::new("loop");
+    let server = Server::start();
+    let (_, res) = download(&dir, &client(), &server.url("/loop"), None, "loop");
+    assert!(format!("{:#}", res.unwrap_err()).contains("Too many redirects"));
+}
+
+#[test]
+fn times_out() {
+    let dir = TestDir::new("slow");
+    let server = Server::start();
+    let mut client = client();
+    client.retries = 0;
+    let (_, res) = download(&dir, &client, &server.url("/slow"), None, "slow");
+    assert!(res.is_err());
+}
+
+#[test]
+fn uses_proxy() {
+    let dir = TestDir::new("proxy");
+    let server = Server::start();
+    let mut client = client();
+    client.proxy = Some(format!("http://{}", server.addr));
+
+    let url = "http://moss.invalid/file".to_string();
+    let (file, res) = download(&dir, &client, &url, None, "proxy");
+    res.unwrap();
+    assert_eq!(fs::read(&file).unwrap(), data());
+    assert_eq!(*server.proxied.lock().unwrap(), vec![url]);
+}
+
+#[test]
+fn resolves_urls() {
+    let base = "https://example.com/a/b/file.tar.gz".to_string();
+    assert_eq!(http::resolve_url(&base, "other.tar.gz").unwrap(), "https://example.com/a/b/other.tar.gz");
+    assert_eq!(http::resolve_url(&base, "../c/file").unwrap(), "https://example.com/a/c/file");
+    assert_eq!(http::resolve_url(&base, "/root").unwrap(), "https://example.com/root");
+    assert_eq!(http::resolve_url(&base, "//cdn.example.com/x").unwrap(), "https://cdn.example.com/x");
+    assert_eq!(http::resolve_url(&base, "http://mirror.example.com/y").unwrap(), "http://mirror.example.com/y");
+}
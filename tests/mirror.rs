@@ -0,0 +1,36 @@
+//! Tests for laying out sources in a mirror.
+
+use moss::mirror::mirror_path;
+
+fn path(url: &str) -> String {
+    mirror_path("/srv/mirror/", url).unwrap()
+}
+
+#[test]
+fn lays_out_by_host_and_path() {
+    assert_eq!(path("https://example.com/releases/foo-1.0.tar.gz"), "/srv/mirror/example.com/releases/foo-1.0.tar.gz");
+    assert_eq!(path("http://example.com:8080/foo.tar.gz"), "/srv/mirror/example.com:8080/foo.tar.gz");
+}
+
+#[test]
+fn ignores_query_and_fragment() {
+    assert_eq!(path("https://example.com/foo.tar.gz?download=1#top"), "/srv/mirror/example.com/foo.tar.gz");
+}
+
+#[test]
+fn decodes_path() {
+    assert_eq!(path("https://example.com/foo%20bar/foo%2B1.tar.gz"), "/srv/mirror/example.com/foo bar/foo+1.tar.gz");
+}
+
+#[test]
+fn stays_inside_host_dir() {
+    assert_eq!(path("https://example.com/a/%2E%2E/%2E%2E/etc/passwd"), "/srv/mirror/example.com/etc/passwd");
+    assert_eq!(path("https://example.com/a/../../../etc/passwd"), "/srv/mirror/example.com/etc/passwd");
+}
+
+#[test]
+fn rejects_urls_without_a_file() {
+    assert!(mirror_path("/srv/mirror", "https://example.com/").is_err());
+    assert!(mirror_path("/srv/mirror", "https://example.com/releases/").is_err());
+    assert!(mirror_path("/srv/mirror", "not a url").is_err());
+}
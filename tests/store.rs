@@ -0,0 +1,79 @@
+//! Tests for the source store, in a temporary directory.
+
+mod common;
+
+use std::fs;
+use std::thread;
+
+use common::TestDir;
+use moss::checksum::{self, Algorithm};
+use moss::store::Store;
+
+/// Make an empty store and download cache in a test dir.
+fn setup(name: &str) -> (TestDir, Store, String) {
+    let dir = TestDir::new(name);
+    let root = dir.path.display().to_string();
+    fs::create_dir_all(format!("{root}/dl")).unwrap();
+    (dir, Store { dir: format!("{root}/store") }, format!("{root}/dl"))
+}
+
+fn hash(data: &str) -> (Algorithm, String) {
+    checksum::parse(&blake3::hash(data.as_bytes()).to_string()).unwrap()
+}
+
+#[test]
+fn inserts_file() {
+    let (_dir, store, dl) = setup("insert");
+    let file = format!("{dl}/foo.tar.gz");
+    fs::write(&file, "foo").unwrap();
+    let (algorithm, hash) = hash("foo");
+
+    assert!(!store.is_linked(&file, algorithm, &hash));
+    store.insert(&file, algorithm, &hash).unwrap();
+    assert!(store.is_linked(&file, algorithm, &hash));
+    assert_eq!(fs::read_link(&file).unwrap().display().to_string(), store.path(algorithm, &hash));
+    assert_eq!(fs::read_to_string(store.path(algorithm, &hash)).unwrap(), "foo");
+}
+
+#[test]
+fn links_stored_file() {
+    let (_dir, store, dl) = setup("link");
+    let (algorithm, hash) = hash("foo");
+    let file = format!("{dl}/foo.tar.gz");
+    assert!(!store.link(&file, algorithm, &hash).unwrap());
+    assert!(fs::symlink_metadata(&file).is_err());
+
+    let other = format!("{dl}/other.tar.gz");
+    fs::write(&other, "foo").unwrap();
+    store.insert(&other, algorithm, &hash).unwrap();
+
+    // A stale copy is replaced by the link.
+    fs::write(&file, "old").unwrap();
+    assert!(store.link(&file, algorithm, &hash).unwrap());
+    assert_eq!(fs::read_to_string(&file).unwrap(), "foo");
+    assert!(store.is_linked(&file, algorithm, &hash));
+}
+
+#[test]
+fn inserts_same_file_in_parallel() {
+    let (_dir, store, dl) = setup("parallel");
+    let (algorithm, hash) = hash("foo");
+    let files: Vec<String> = (0..8).map(|x| format!("{dl}/foo-{x}.tar.gz")).collect();
+    for file in &files {
+        fs::write(file, "foo").unwrap();
+    }
+
+    thread::scope(|s| {
+        for file in &files {
+            s.spawn(|| store.insert(file, algorithm, &hash).unwrap());
+        }
+    });
+
+    for file in &files {
+        assert!(store.is_linked(file, algorithm, &hash));
+    }
+
+    // No part files are left behind.
+    let entries = fs::read_dir(format!("{}/{algorithm}", store.dir)).unwrap().count();
+    assert_eq!(entries, 1);
+}